@@ -1,9 +1,11 @@
 extern crate hyperspeed;
+#[macro_use]
+extern crate serde_derive;
 
 use hyperspeed::{System, WriteStorage, ReadStorage,
-                 Read, WriteViewMap, Entities, WriteConnections,
+                 Read, WriteViewMap, Entities, WriteConnections, ReadAssets,
                  define_component, Component, VecStorage, Join};
-use hyperspeed::core::{World, Engine, MasterController, EngineInstruction, ClientView, StreamData};
+use hyperspeed::core::{World, Engine, MasterController, EngineInstruction, ClientView, StreamData, Transition, Stage, ZLevels, EntityView, ActionMap, ActionStates};
 
 use std::thread::sleep;
 use std::time::Duration;
@@ -13,7 +15,7 @@ use std::io::Read as R;
 use specs::world::EntitiesRes;
 use hyperspeed::utils::server::read_message_from_stream;
 use hyperspeed::utils::server::StreamReadResult::{ValidMessage, StreamError, InvalidMessage};
-use hyperspeed::components::Visible;
+use hyperspeed::components::{Visible, NetworkId, DEFAULT_Z_LEVEL};
 
 
 struct Position {
@@ -41,11 +43,23 @@ fn start_game(world: &mut World) -> bool {
 struct MoveSystem {}
 
 impl<'a> System<'a> for MoveSystem {
-    type SystemData = WriteStorage<'a, Position>;
+    type SystemData = (Read<'a, ActionStates<Message>>, ReadStorage<'a, PlayerControllable>, WriteStorage<'a, Position>);
 
-    fn run(&mut self, mut pos: Self::SystemData) {
-        for mut p in (&mut pos).join() {
-            p.x += 0.1;
+    fn run(&mut self, (actions, players, mut pos): Self::SystemData) {
+        for (pc, p) in (&players, &mut pos).join() {
+            let key = &pc.player_key;
+            if actions.is_down(key, &Message::Up) {
+                p.y -= 1.0;
+            }
+            if actions.is_down(key, &Message::Down) {
+                p.y += 1.0;
+            }
+            if actions.is_down(key, &Message::Left) {
+                p.x -= 1.0;
+            }
+            if actions.is_down(key, &Message::Right) {
+                p.x += 1.0;
+            }
         }
     }
 }
@@ -55,15 +69,15 @@ struct ConnectionSystem {
 }
 
 impl<'a> System<'a> for ConnectionSystem {
-    type SystemData = (Entities<'a>, WriteConnections<'a>, WriteStorage<'a, Position>, WriteStorage<'a, PlayerControllable>, WriteStorage<'a, Visible>);
+    type SystemData = (Entities<'a>, WriteConnections<'a>, ReadAssets<'a>, WriteStorage<'a, Position>, WriteStorage<'a, PlayerControllable>, WriteStorage<'a, Visible>);
 
-    fn run(&mut self, (entities, mut connections, mut pos, mut player_controllable, mut visible): Self::SystemData) {
+    fn run(&mut self, (entities, mut connections, assets, mut pos, mut player_controllable, mut visible): Self::SystemData) {
         for key in (*connections).pop_new_keys() {
             println!("Making new entity!!");
             entities.build_entity()
                 .with(PlayerControllable { player_key: key }, &mut player_controllable)
                 .with(Position { x: 100.0, y: 100.0 }, &mut pos)
-                .with(Visible { sprite: 0 }, &mut visible)
+                .with(Visible { sprite: assets.sprite_id("player").unwrap_or(0) }, &mut visible)
                 .build();
         }
     }
@@ -72,17 +86,13 @@ impl<'a> System<'a> for ConnectionSystem {
 struct RenderSystem {}
 
 impl<'a> System<'a> for RenderSystem {
-    type SystemData = (WriteViewMap<'a>, Read<'a, bool>, ReadStorage<'a, Position>, ReadStorage<'a, PlayerControllable>);
-    fn run(&mut self, (mut view_map, should_render, positions, players): Self::SystemData) {
+    type SystemData = (ReadStorage<'a, NetworkId>, WriteViewMap<'a>, Read<'a, bool>, Read<'a, ZLevels>, ReadStorage<'a, Position>, ReadStorage<'a, PlayerControllable>);
+    fn run(&mut self, (network_ids, mut view_map, should_render, z_levels, positions, players): Self::SystemData) {
         if *should_render {
             for pc in players.join() {
-                let mut view = ClientView {
-                    sprites: vec!(),
-                    loc: vec!()
-                };
-                for p in positions.join() {
-                    view.sprites.push(0);
-                    view.loc.push((p.x, p.y));
+                let mut view = ClientView::new();
+                for (net, p) in (&network_ids, &positions).join() {
+                    view.push(EntityView::new(net.id, 0, (p.x, p.y)), z_levels.get(DEFAULT_Z_LEVEL));
                 }
                 view_map.insert(pc.player_key.clone(), view);
             }
@@ -90,29 +100,55 @@ impl<'a> System<'a> for RenderSystem {
     }
 }
 
-struct MC {}
+fn regulate(world: &mut World) -> EngineInstruction {
+    // Regulate ticks
+    sleep(Duration::from_millis(20));
+    if world.connections.size() > 0 {
+        world.ecs_world.add_resource(true);
+    } else {
+        world.ecs_world.add_resource(false);
+    }
+    EngineInstruction::Run {
+        run_dispatcher: true
+    }
+}
+
+struct Lobby {}
 
-impl MasterController for MC {
+impl MasterController for Lobby {
     type ObserverEvent = Message;
 
-    fn start(&mut self, world: &mut World, dt: f64) {
-
+    fn on_enter(&mut self, world: &mut World) {
+        world.disable_system_group("match");
     }
 
     fn tick(&mut self, world: &mut World, dt: f64) -> EngineInstruction {
-        // Regulate ticks
-        sleep(Duration::from_millis(20));
-        if world.connections.size() > 0 {
-            world.ecs_world.add_resource(true);
+        regulate(world)
+    }
+
+    fn transition(&mut self, world: &mut World) -> Transition<Message> {
+        if start_game(world) {
+            Transition::Switch(Box::new(Match {}))
         } else {
-            world.ecs_world.add_resource(false);
-        }
-        EngineInstruction::Run {
-            run_dispatcher: true
+            Transition::None
         }
     }
 }
 
+struct Match {}
+
+impl MasterController for Match {
+    type ObserverEvent = Message;
+
+    fn on_enter(&mut self, world: &mut World) {
+        world.enable_system_group("match");
+    }
+
+    fn tick(&mut self, world: &mut World, dt: f64) -> EngineInstruction {
+        regulate(world)
+    }
+}
+
 fn process_stream(mut stream: &mut TcpStream) -> StreamData {
     let mut buffer = BytesMut::new();
     buffer.reserve(512);
@@ -125,7 +161,7 @@ fn process_stream(mut stream: &mut TcpStream) -> StreamData {
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Deserialize)]
 enum Message {
     Up,
     Down,
@@ -134,15 +170,26 @@ enum Message {
 }
 
 fn main() {
-    let mut engine = Engine::<Message>::new().with_mc(MC {})
+    let actions = ActionMap::<Message>::load_file("examples/actions.json").unwrap_or_else(|e| {
+        println!("{}", e);
+        ActionMap::<Message>::new()
+    });
+    let mut engine = Engine::<Message>::new().with_mc(Lobby {})
+        .with_action_map(actions)
+        .in_stage(Stage::PreUpdate)
         .with_system(ConnectionSystem {}, "c", &[])
-        .with_system(MoveSystem {}, "m", &["c"])
-        .with_system(RenderSystem {}, "render", &["c", "m"])
+        .in_stage(Stage::Update)
+        .with_grouped_system(MoveSystem {}, "m", &[], "match")
+        .in_stage(Stage::View)
+        .with_system(RenderSystem {}, "render", &[])
         .with_stream_handler(process_stream)
         .build();
     if let Some(mut engine) = engine {
         engine.register::<Position>();
         engine.register::<PlayerControllable>();
+        if let Err(e) = engine.load_assets("examples/assets.json") {
+            println!("{}", e);
+        }
         engine.start_server();
         loop {
             engine.tick();
@@ -0,0 +1,269 @@
+use super::AssetRegistry;
+use crate::components::{Frame, Visible};
+use crate::core::ServerTime;
+use specs::prelude::*;
+use shrev::EventChannel;
+
+/// A named sequence of a sprite's frames, listed in the manifest under the sprite's
+/// `animations`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AnimationDef {
+    /// Indices into the sprite's `frames`, in the order they're played.
+    pub frames: Vec<u32>,
+    /// Frames per second at a speed of 1.0.
+    #[serde(default = "default_fps")]
+    pub fps: f32
+}
+
+fn default_fps() -> f32 {
+    10.0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum PlayMode {
+    Loop,
+    /// Stops on the last frame.
+    Once,
+    /// Plays forwards, then backwards, then forwards again.
+    PingPong
+}
+
+impl Default for PlayMode {
+    fn default() -> Self {
+        PlayMode::Loop
+    }
+}
+
+/// Plays one of the animations of the entity's `Visible` sprite by setting its `Frame`, see
+/// `AnimationSystem`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Animation {
+    pub name: String,
+    #[serde(default = "default_speed")]
+    pub speed: f32,
+    #[serde(default)]
+    pub mode: PlayMode,
+    // Seconds of animation played so far, at speed 1.0
+    #[serde(skip)]
+    elapsed: f32,
+    #[serde(skip)]
+    finished: bool
+}
+
+fn default_speed() -> f32 {
+    1.0
+}
+
+impl Animation {
+    pub fn new(name: &str, mode: PlayMode) -> Self {
+        Animation {
+            name: name.to_string(),
+            speed: 1.0,
+            mode,
+            elapsed: 0.0,
+            finished: false
+        }
+    }
+
+    /// Switches to another animation from its first frame. Playing the animation that's
+    /// already playing does nothing.
+    pub fn play(&mut self, name: &str, mode: PlayMode) {
+        if self.name != name || self.mode != mode {
+            *self = Animation {
+                speed: self.speed,
+                ..Animation::new(name, mode)
+            };
+        }
+    }
+
+    /// Starts the animation over.
+    pub fn restart(&mut self) {
+        self.elapsed = 0.0;
+        self.finished = false;
+    }
+
+    /// Whether an animation played `Once` has reached its last frame.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+}
+
+crate::define_component!(Animation);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AnimationEvent {
+    /// An animation played `Once` reached its last frame.
+    Finished { entity: Entity, name: String },
+    /// A looping or ping-pong animation came back to its first frame.
+    Looped { entity: Entity, name: String }
+}
+
+pub type AnimationEvents = EventChannel<AnimationEvent>;
+
+// Moves every `Animation` on by `ServerTime::fixed_delta` each tick, so animations play at
+// the same rate however long ticks take, and sets the entity's `Frame` to match. Frames are
+// sent to clients in views like any other `Frame`. Animations the entity's sprite doesn't have
+// are left alone.
+pub struct AnimationSystem;
+
+impl<'a> System<'a> for AnimationSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, ServerTime>,
+    Read<'a, AssetRegistry>,
+    Write<'a, AnimationEvents>,
+    ReadStorage<'a, Visible>,
+    WriteStorage<'a, Animation>,
+    WriteStorage<'a, Frame>);
+
+    fn run(&mut self, (entities, time, assets, mut events, visibles, mut animations, mut frames): Self::SystemData) {
+        let mut new_frames = vec!();
+
+        for (entity, visible, animation) in (&entities, &visibles, &mut animations).join() {
+            let def = match assets.animation(visible.sprite, &animation.name) {
+                Some(def) if def.frames.len() > 0 => def,
+                _ => continue
+            };
+            if animation.finished {
+                continue;
+            }
+
+            let len = def.frames.len();
+            let before = (animation.elapsed * def.fps) as usize;
+            animation.elapsed += time.fixed_delta as f32 * animation.speed.max(0.0);
+            let step = (animation.elapsed * def.fps) as usize;
+
+            let index = match animation.mode {
+                PlayMode::Once => {
+                    if step >= len - 1 {
+                        animation.finished = true;
+                        events.single_write(AnimationEvent::Finished { entity, name: animation.name.clone() });
+                    }
+                    step.min(len - 1)
+                },
+                PlayMode::Loop => {
+                    if step / len > before / len {
+                        events.single_write(AnimationEvent::Looped { entity, name: animation.name.clone() });
+                    }
+                    step % len
+                },
+                PlayMode::PingPong => {
+                    let period = (len * 2 - 2).max(1);
+                    if step / period > before / period {
+                        events.single_write(AnimationEvent::Looped { entity, name: animation.name.clone() });
+                    }
+                    let k = step % period;
+                    if k < len { k } else { period - k }
+                }
+            };
+            new_frames.push((entity, def.frames[index]));
+        }
+
+        for (entity, index) in new_frames {
+            if frames.insert(entity, Frame { index }).is_err() {
+                // Only dead entities can't be given one, and they aren't drawn
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A torch whose animations step one frame every tick, apart from "flicker" which steps
+    // three quarters of a frame
+    struct Torch {
+        world: World,
+        entity: Entity,
+        reader: ReaderId<AnimationEvent>
+    }
+
+    impl Torch {
+        fn new(name: &str, mode: PlayMode) -> Torch {
+            let assets = AssetRegistry::load_str(r#"{ "sprites": { "torch": {
+                "region": [0, 0, 64, 16],
+                "frames": [[0, 0, 16, 16], [16, 0, 16, 16], [32, 0, 16, 16], [48, 0, 16, 16]],
+                "animations": {
+                    "burn": { "frames": [0, 1, 2, 3], "fps": 4.0 },
+                    "blink": { "frames": [0, 1], "fps": 4.0 },
+                    "flicker": { "frames": [0, 1, 2, 3], "fps": 3.0 }
+                }
+            } } }"#).unwrap();
+            let sprite = assets.sprite_id("torch").unwrap();
+
+            let mut world = World::new();
+            world.register::<Visible>();
+            world.register::<Animation>();
+            world.register::<Frame>();
+            world.add_resource(assets);
+            world.add_resource(ServerTime {
+                fixed_delta: 0.25,
+                ..ServerTime::default()
+            });
+            world.add_resource(AnimationEvents::new());
+            let reader = world.write_resource::<AnimationEvents>().register_reader();
+            let entity = world.create_entity()
+                .with(Visible { sprite })
+                .with(Animation::new(name, mode))
+                .build();
+            Torch { world, entity, reader }
+        }
+
+        // Runs `n` ticks and returns the frame after each, with the ticks events were sent on
+        fn run(&mut self, n: usize) -> (Vec<u32>, Vec<(usize, AnimationEvent)>) {
+            let mut frames = vec!();
+            let mut events = vec!();
+            for tick in 0..n {
+                AnimationSystem.run_now(&self.world.res);
+                self.world.maintain();
+                frames.push(self.world.read_storage::<Frame>().get(self.entity).unwrap().index);
+                for event in self.world.read_resource::<AnimationEvents>().read(&mut self.reader) {
+                    events.push((tick, event.clone()));
+                }
+            }
+            (frames, events)
+        }
+
+        fn looped(&self, name: &str) -> AnimationEvent {
+            AnimationEvent::Looped { entity: self.entity, name: name.to_string() }
+        }
+    }
+
+    #[test]
+    fn loops_go_back_to_the_first_frame() {
+        let mut torch = Torch::new("burn", PlayMode::Loop);
+        let (frames, events) = torch.run(9);
+        assert_eq!(frames, vec!(1, 2, 3, 0, 1, 2, 3, 0, 1));
+        assert_eq!(events, vec!((3, torch.looped("burn")), (7, torch.looped("burn"))));
+    }
+
+    #[test]
+    fn once_stops_on_the_last_frame() {
+        let mut torch = Torch::new("burn", PlayMode::Once);
+        let (frames, events) = torch.run(6);
+        assert_eq!(frames, vec!(1, 2, 3, 3, 3, 3));
+        assert_eq!(events, vec!((2, AnimationEvent::Finished { entity: torch.entity, name: "burn".to_string() })));
+        assert!(torch.world.read_storage::<Animation>().get(torch.entity).unwrap().is_finished());
+    }
+
+    #[test]
+    fn ping_pong_plays_the_first_and_last_frames_once() {
+        let mut torch = Torch::new("burn", PlayMode::PingPong);
+        let (frames, events) = torch.run(8);
+        assert_eq!(frames, vec!(1, 2, 3, 2, 1, 0, 1, 2));
+        assert_eq!(events, vec!((5, torch.looped("burn"))));
+
+        let mut torch = Torch::new("blink", PlayMode::PingPong);
+        let (frames, events) = torch.run(4);
+        assert_eq!(frames, vec!(1, 0, 1, 0));
+        assert_eq!(events, vec!((1, torch.looped("blink")), (3, torch.looped("blink"))));
+    }
+
+    #[test]
+    fn part_frames_carry_over_to_the_next_tick() {
+        let mut torch = Torch::new("flicker", PlayMode::Loop);
+        let (frames, events) = torch.run(8);
+        assert_eq!(frames, vec!(0, 1, 2, 3, 3, 0, 1, 2));
+        assert_eq!(events, vec!((5, torch.looped("flicker"))));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::components::SpriteID;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+
+mod animation;
+
+pub use animation::{AnimationDef, PlayMode, Animation, AnimationEvent, AnimationEvents, AnimationSystem};
+
+/// A rectangle in a texture atlas: x, y, width, height in pixels.
+pub type Region = (u32, u32, u32, u32);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SpriteAsset {
+    /// Left out of the manifest file to have one picked, see `AssetRegistry`.
+    #[serde(default)]
+    pub id: Option<SpriteID>,
+    #[serde(default)]
+    pub atlas: Option<String>,
+    pub region: Region,
+    /// The size the sprite is drawn at, if it isn't the size of its region.
+    #[serde(default)]
+    pub size: Option<(u32, u32)>,
+    /// The point the sprite is drawn around, from (0.0, 0.0) top left to (1.0, 1.0) bottom right.
+    #[serde(default)]
+    pub pivot: (f32, f32),
+    /// The regions of every animation frame, `Frame::index` picks one of these.
+    #[serde(default)]
+    pub frames: Vec<Region>,
+    #[serde(default)]
+    pub animations: BTreeMap<String, AnimationDef>
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct AssetManifest {
+    pub sprites: BTreeMap<String, SpriteAsset>
+}
+
+// The sprites a game knows about, loaded from a manifest like
+//
+// {
+//     "sprites": {
+//         "player": { "atlas": "chars.png", "region": [0, 0, 32, 32], "pivot": [0.5, 1.0] },
+//         "wall": { "id": 7, "atlas": "tiles.png", "region": [32, 0, 16, 16] },
+//         "torch": {
+//             "atlas": "tiles.png", "region": [0, 16, 16, 16],
+//             "frames": [[0, 16, 16, 16], [16, 16, 16, 16]],
+//             "animations": { "burn": { "frames": [0, 1], "fps": 8.0 } }
+//         }
+//     }
+// }
+//
+// Sprites without an id are given the lowest free ids in name order, so ids stay the same as
+// long as the manifest does. The whole manifest is sent to clients when they connect, unless
+// they already have one with the same hash.
+#[derive(Clone, Debug, Default)]
+pub struct AssetRegistry {
+    manifest: AssetManifest,
+    ids: HashMap<String, SpriteID>,
+    names: HashMap<SpriteID, String>,
+    hash: String
+}
+
+impl AssetRegistry {
+    pub fn new() -> Self {
+        AssetRegistry::from_manifest(AssetManifest::default()).expect("Engine fault: An empty manifest could not be loaded")
+    }
+
+    /// Fails if two sprites are given the same id, or an animation uses a frame its sprite
+    /// doesn't have.
+    pub fn from_manifest(mut manifest: AssetManifest) -> Result<Self, String> {
+        let mut taken: HashMap<SpriteID, &str> = HashMap::new();
+        for (name, sprite) in &manifest.sprites {
+            for (animation, def) in &sprite.animations {
+                if let Some(frame) = def.frames.iter().find(|frame| **frame as usize >= sprite.frames.len()) {
+                    return Err(format!("Animation {} of sprite {} uses frame {}, but the sprite has {} frames",
+                        animation, name, frame, sprite.frames.len()));
+                }
+            }
+            if let Some(id) = sprite.id {
+                if let Some(other) = taken.insert(id, name) {
+                    return Err(format!("Sprites {} and {} both have id {}", other, name, id));
+                }
+            }
+        }
+        let taken: HashSet<SpriteID> = taken.keys().cloned().collect();
+        let mut next: SpriteID = 0;
+        for sprite in manifest.sprites.values_mut() {
+            if sprite.id.is_none() {
+                while taken.contains(&next) {
+                    next += 1;
+                }
+                sprite.id = Some(next);
+                next += 1;
+            }
+        }
+
+        let ids: HashMap<String, SpriteID> = manifest.sprites.iter()
+            .map(|(name, sprite)| (name.clone(), sprite.id.unwrap()))
+            .collect();
+        let names = ids.iter().map(|(name, id)| (*id, name.clone())).collect();
+        let hash = format!("{:016x}", fnv1a(serde_json::to_string(&manifest).unwrap().as_bytes()));
+        Ok(AssetRegistry {
+            manifest,
+            ids,
+            names,
+            hash
+        })
+    }
+
+    pub fn load_str(json: &str) -> Result<Self, String> {
+        let manifest = serde_json::from_str(json)
+            .map_err(|e| format!("Asset manifest could not be parsed: {}", e))?;
+        AssetRegistry::from_manifest(manifest)
+    }
+
+    pub fn load_file(path: &str) -> Result<Self, String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Asset manifest {} could not be read: {}", path, e))?;
+        AssetRegistry::load_str(&json)
+    }
+
+    pub fn sprite_id(&self, name: &str) -> Option<SpriteID> {
+        self.ids.get(name).cloned()
+    }
+
+    pub fn sprite(&self, name: &str) -> Option<&SpriteAsset> {
+        self.manifest.sprites.get(name)
+    }
+
+    pub fn sprite_name(&self, id: SpriteID) -> Option<&str> {
+        self.names.get(&id).map(|name| name.as_str())
+    }
+
+    pub fn animation(&self, id: SpriteID, name: &str) -> Option<&AnimationDef> {
+        self.sprite(self.sprite_name(id)?)?.animations.get(name)
+    }
+
+    pub fn manifest(&self) -> &AssetManifest {
+        &self.manifest
+    }
+
+    /// A hash of the manifest as it's sent to clients.
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+}
+
+// FNV-1a, which unlike std's hashers is guaranteed to give the same hash on every build
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_free_ids() {
+        let assets = AssetRegistry::load_str(r#"{ "sprites": {
+            "a": { "region": [0, 0, 1, 1] },
+            "b": { "id": 0, "region": [0, 0, 1, 1] },
+            "c": { "region": [0, 0, 1, 1] }
+        } }"#).unwrap();
+        assert_eq!((assets.sprite_id("a"), assets.sprite_id("b"), assets.sprite_id("c")), (Some(1), Some(0), Some(2)));
+        assert_eq!(assets.sprite_name(2), Some("c"));
+    }
+
+    #[test]
+    fn rejects_duplicate_ids() {
+        let result = AssetRegistry::load_str(r#"{ "sprites": {
+            "a": { "id": 3, "region": [0, 0, 1, 1] },
+            "b": { "region": [0, 0, 1, 1] },
+            "c": { "id": 3, "region": [0, 0, 1, 1] }
+        } }"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_animations_past_the_last_frame() {
+        let result = AssetRegistry::load_str(r#"{ "sprites": { "torch": {
+            "region": [0, 0, 16, 16],
+            "frames": [[0, 0, 16, 16], [16, 0, 16, 16]],
+            "animations": { "burn": { "frames": [0, 1, 2] } }
+        } } }"#);
+        assert!(result.is_err());
+    }
+}
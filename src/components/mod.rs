@@ -1,30 +1,136 @@
 use super::*;
 
-pub type ZLevelID = &'static str;
+pub type ZLevelID = String;
 pub type SpriteID = u64;
 
+/// The z-level entities are on unless they say otherwise, see `ZLevels`.
+pub const DEFAULT_Z_LEVEL: &str = "default";
+
+fn default_z_level() -> ZLevelID {
+    DEFAULT_Z_LEVEL.to_string()
+}
+
+#[derive(Deserialize)]
 pub struct Position {
     pub x: f32,
     pub y: f32,
-    z_level: ZLevelID
+    #[serde(default = "default_z_level")]
+    pub z_level: ZLevelID
 }
 
+#[derive(Deserialize)]
 pub struct Visible {
     pub sprite: SpriteID
 }
 
+#[derive(Deserialize)]
 pub struct PositionTiled {
     pub x: u32,
     pub y: u32,
+    #[serde(default = "default_z_level")]
     pub z_level: ZLevelID
 }
 
+/// Decides what the connection with the login key `key` can see. Entities are only sent if
+/// they're within `view_range` of the camera's `Position` moved by `offset`.
+#[derive(Deserialize)]
 pub struct Camera {
+    #[serde(default)]
+    pub key: String,
     pub view_range: u16,
     pub offset: (u32, u32)
 }
 
-define_component!(Position);
+/// Rotation in radians, clockwise.
+#[derive(Deserialize)]
+pub struct Rotation {
+    pub angle: f32
+}
+
+#[derive(Deserialize)]
+pub struct Scale {
+    pub x: f32,
+    pub y: f32
+}
+
+#[derive(Deserialize)]
+pub struct Flip {
+    pub horizontal: bool,
+    pub vertical: bool
+}
+
+/// A colour the sprite is multiplied by.
+#[derive(Deserialize)]
+pub struct Tint {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+/// From 0.0 (invisible) to 1.0 (opaque).
+#[derive(Deserialize)]
+pub struct Opacity {
+    pub alpha: f32
+}
+
+/// Which frame of the sprite to draw.
+#[derive(Deserialize)]
+pub struct Frame {
+    pub index: u32
+}
+
+/// Orders entities on the same z-level, lower first.
+#[derive(Deserialize)]
+pub struct ZOrder {
+    pub order: i32
+}
+
+/// Identifies an entity to clients. Given to every `Visible` entity by `NetworkIdSystem`.
+pub struct NetworkId {
+    pub id: u64
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32) -> Self {
+        Position::on_level(x, y, DEFAULT_Z_LEVEL)
+    }
+
+    pub fn on_level(x: f32, y: f32, z_level: &str) -> Self {
+        Position {
+            x,
+            y,
+            z_level: z_level.to_string()
+        }
+    }
+}
+
+impl PositionTiled {
+    pub fn new(x: u32, y: u32) -> Self {
+        PositionTiled::on_level(x, y, DEFAULT_Z_LEVEL)
+    }
+
+    pub fn on_level(x: u32, y: u32, z_level: &str) -> Self {
+        PositionTiled {
+            x,
+            y,
+            z_level: z_level.to_string()
+        }
+    }
+}
+
+// Flagged so `SpatialIndexSystem` only has to look at positions that changed
+impl Component for Position {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
 define_component!(Visible);
 define_component!(PositionTiled);
-define_component!(Camera);
\ No newline at end of file
+define_component!(Camera);
+define_component!(Rotation);
+define_component!(Scale);
+define_component!(Flip);
+define_component!(Tint);
+define_component!(Opacity);
+define_component!(Frame);
+define_component!(ZOrder);
+define_component!(NetworkId);
@@ -6,57 +6,134 @@ use crate::utils::*;
 
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::{channel, Sender, Receiver};
-use std::thread::spawn;
+use std::thread::{spawn, sleep};
 
 use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
 use specs::Component;
 use super::server::InputBufferMutex;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use crate::core::world::Connection;
 use crate::core::server::StreamData;
 use std::net::TcpStream;
-use crate::components::{Position, Camera, Visible};
+use crate::components::*;
+use serde::de::DeserializeOwned;
+use crate::spatial::{SpatialIndex, SpatialIndexSystem};
+use crate::systems::NetworkIdSystem;
+use crate::assets::{AssetRegistry, Animation, AnimationEvents, AnimationSystem};
+use crate::tiles::{TileMap, TileSyncSystem, PathfindingSystem, PathRequest, Path, NoPath, MapObject};
+use crate::tiles::{GridConfig, GridSync, GridMover, GridMovementSystem, GridSyncSystem};
+use crate::lighting::{LightingConfig, LightingSystem, LightSyncSystem, LightSource, Occluder};
+use crate::physics::{PhysicsConfig, PhysicsSystem, CollisionEvents, Velocity, Acceleration, Collider};
+use crate::physics::{TriggerSystem, TriggerEvents, TriggerZone, Tags};
 
 pub struct Engine<'a, 'b, E: Sync + Send + Clone + 'static> {
     pub world: World<'a, 'b>,
-    master_controller: Box<MasterController<ObserverEvent=E>>,
+    states: StateStack<E>,
     input_buffer: Option<InputBufferMutex>,
     server_conf: ServerConfig,
     prev_time: Instant,
+    start_time: Instant,
     connection_channel: Receiver<(Connection, Sender<ClientView>)>,
     view_channels: HashMap<String, Sender<ClientView>>,
-    server_stream_handler: Option<StreamHandler>
+    server_stream_handler: Option<StreamHandler>,
+    next_instruction: Option<EngineInstruction>,
+    // The shortest a tick can take, if the engine was given a tick rate
+    tick_interval: Option<Duration>
 }
 
 pub struct EngineBuilder<'a, 'b, E: Sync + Send + Clone + 'static> {
     server_conf: ServerConfig,
     system_executor_builder: SystemExecutorBuilder<'a, 'b>,
     master_controller: Option<Box<MasterController<ObserverEvent=E>>>,
-    server_stream_handler: Option<StreamHandler>
+    server_stream_handler: Option<StreamHandler>,
+    spatial_cell_size: Option<f32>,
+    tile_map: Option<(TileMap, u32)>,
+    lighting: Option<LightingConfig>,
+    grid: Option<GridConfig>,
+    physics: Option<PhysicsConfig>,
+    triggers: bool,
+    tick_rate: Option<f64>
 }
 
 impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
     pub fn new() -> EngineBuilder<'a, 'b, E> {
+        // Timers fire and input states are brought up to date in a stage of their own, so
+        // they're done before any of the game's systems run
+        let mut system_executor_builder = SystemExecutor::new();
+        system_executor_builder.set_stage(Stage::Engine);
+        system_executor_builder.add_system(SchedulerSystem::<E>::new(), "scheduler", &[]);
+        system_executor_builder.add_system(InputStateSystem, "input_state", &[]);
+        system_executor_builder.set_stage(Stage::Update);
+
         EngineBuilder {
             server_conf: ServerConfig::new(),
-            system_executor_builder: SystemExecutor::new(),
+            system_executor_builder,
             master_controller: None,
-            server_stream_handler: None
+            server_stream_handler: None,
+            spatial_cell_size: None,
+            tile_map: None,
+            lighting: None,
+            grid: None,
+            physics: None,
+            triggers: false,
+            tick_rate: None
         }
     }
 
     pub fn init_resources(&mut self) {
         // This is the event/messaging
         self.world.ecs_world.add_resource(Messages::<E>::new());
+        self.world.ecs_world.add_resource(Scheduler::<E>::new());
         self.world.ecs_world.add_resource(InputMap::new());
+        self.world.ecs_world.add_resource(InputAcks::new());
+        self.world.ecs_world.add_resource(InputStates::new());
         self.world.ecs_world.add_resource(ViewMap::new());
         self.world.ecs_world.add_resource(ConnectionCollection::new());
+        self.world.ecs_world.add_resource(ZLevels::new());
+        self.world.ecs_world.add_resource(NetworkIdAllocator::new());
+        self.world.ecs_world.add_resource(ServerTime::default());
+        self.world.ecs_world.add_resource(GridConfig::default());
+        self.world.ecs_world.add_resource(CollisionEvents::new());
+        self.world.ecs_world.add_resource(TriggerEvents::new());
+        self.world.ecs_world.add_resource(AnimationEvents::new());
+        self.world.ecs_world.add_resource(AssetRegistry::new());
 
         // Register default components
 
         self.world.ecs_world.register::<Position>();
         self.world.ecs_world.register::<Visible>();
         self.world.ecs_world.register::<Camera>();
+        self.world.ecs_world.register::<PositionTiled>();
+        let ecs_world = &mut self.world.ecs_world;
+        crate::register_components!(ecs_world, Rotation, Scale, Flip, Tint, Opacity, Frame, ZOrder, NetworkId,
+            PathRequest, Path, NoPath, MapObject, GridSync, GridMover, LightSource, Occluder,
+            Velocity, Acceleration, Collider, TriggerZone, Tags, Animation);
+
+        let mut blueprints = BlueprintRegistry::new();
+        blueprints.register_component::<Position>("Position");
+        blueprints.register_component::<Visible>("Visible");
+        blueprints.register_component::<Camera>("Camera");
+        blueprints.register_component::<PositionTiled>("PositionTiled");
+        blueprints.register_component::<Rotation>("Rotation");
+        blueprints.register_component::<Scale>("Scale");
+        blueprints.register_component::<Flip>("Flip");
+        blueprints.register_component::<Tint>("Tint");
+        blueprints.register_component::<Opacity>("Opacity");
+        blueprints.register_component::<Frame>("Frame");
+        blueprints.register_component::<ZOrder>("ZOrder");
+        blueprints.register_component::<Animation>("Animation");
+        blueprints.register_component::<PathRequest>("PathRequest");
+        blueprints.register_component::<GridSync>("GridSync");
+        blueprints.register_component::<GridMover>("GridMover");
+        blueprints.register_component::<LightSource>("LightSource");
+        blueprints.register_component::<Occluder>("Occluder");
+        blueprints.register_component::<Velocity>("Velocity");
+        blueprints.register_component::<Acceleration>("Acceleration");
+        blueprints.register_component::<Collider>("Collider");
+        blueprints.register_component::<TriggerZone>("TriggerZone");
+        blueprints.register_component::<Tags>("Tags");
+        self.world.ecs_world.add_resource(blueprints);
     }
 
     pub fn register<T: Component>(&mut self)
@@ -64,6 +141,32 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
         self.world.ecs_world.register::<T>();
     }
 
+    /// Registers a component and makes it usable from blueprint files under `name`.
+    pub fn register_blueprint_component<T>(&mut self, name: &str)
+    where T: Component + DeserializeOwned + Send + Sync,
+          <T as Component>::Storage : std::default::Default {
+        self.world.ecs_world.register::<T>();
+        self.world.ecs_world.write_resource::<BlueprintRegistry>().register_component::<T>(name);
+    }
+
+    pub fn load_blueprints(&mut self, path: &str) -> Result<(), String> {
+        self.world.ecs_world.write_resource::<BlueprintRegistry>().load_file(path)
+    }
+
+    /// Loads the asset manifest systems look sprites up in, which is also sent to clients when
+    /// they connect. This has to be called before `start_server`.
+    pub fn load_assets(&mut self, path: &str) -> Result<(), String> {
+        let assets = AssetRegistry::load_file(path)?;
+        self.server_conf.assets = Some(Arc::new(assets.clone()));
+        self.world.ecs_world.add_resource(assets);
+        Ok(())
+    }
+
+    /// Adds a z-level entities can be placed on, see `ZLevels`.
+    pub fn register_z_level(&mut self, name: &str, order: i32, parallax: (f32, f32)) {
+        self.world.ecs_world.write_resource::<ZLevels>().register(name, order, parallax);
+    }
+
     pub fn start_server(&mut self) {
         fn default(t: &mut TcpStream) -> StreamData {
             StreamData::do_connect_str("default_key")
@@ -79,7 +182,9 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
         let handler = handler
             .unwrap_or(default);
 
-        let mut server = Server::new(self.server_conf.clone(), sender, handler);
+        self.start_time = Instant::now();
+
+        let mut server = Server::new(self.server_conf.clone(), sender, handler, self.start_time);
 
         self.connection_channel = reciever;
 
@@ -87,10 +192,11 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
 
         spawn( move || server.main_loop());
 
-        self.prev_time = Instant::now();
+        self.prev_time = self.start_time;
 
         // Call MC init
-        self.master_controller.start(&mut self.world, 0.0);
+        self.states.active().start(&mut self.world, 0.0);
+        self.states.active().on_enter(&mut self.world);
     }
 
     fn get_new_connection(&mut self) -> Option<(Connection, Sender<ClientView>)> {
@@ -103,13 +209,14 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
         }
     }
 
-    fn get_inputs(&mut self) -> HashMap<String, VecDeque<Input>> {
+    fn get_inputs(&mut self) -> (InputMap, InputAcks) {
         let mut lock = self.input_buffer.as_mut().unwrap().lock();
         match lock {
             Ok(ref mut lock) => {
                 let mut input_map = HashMap::new();
                 ::std::mem::swap(&mut input_map, lock);
-                input_map
+                // Everything taken out of the buffer is applied this tick
+                (input_map, lock.acks().clone())
             }
             _ => {
                 panic!("The input buffer mutex was poisoned!");
@@ -119,10 +226,27 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
     }
     
     pub fn tick(&mut self) {
+        if let Some(interval) = self.tick_interval {
+            let elapsed = self.prev_time.elapsed();
+            if elapsed < interval {
+                sleep(interval - elapsed);
+            }
+        }
+
         let tmp = self.prev_time;
         self.prev_time = Instant::now();
         let time = self.prev_time - tmp;
-        let instruction = self.master_controller.tick(&mut self.world, time.as_float_secs());
+
+        let mut time_ref = self.world.ecs_world.write_resource::<ServerTime>();
+        time_ref.tick += 1;
+        time_ref.delta = time.as_float_secs();
+        time_ref.time = (self.prev_time - self.start_time).as_float_secs();
+        let stamp = (time_ref.tick, time_ref.time);
+        drop(time_ref);
+
+        let instruction = self.states.active().tick(&mut self.world, time.as_float_secs());
+        // An instruction returned from the last tick's events takes precedence
+        let instruction = self.next_instruction.take().unwrap_or(instruction);
 
         let mut new_connection = self.get_new_connection();
 
@@ -137,8 +261,10 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
             new_connection = self.get_new_connection();
         }
 
+        // Systems get a copy of the live connections every tick. New keys stay in the copy
+        // until a tick that runs the systems has handed them out.
         let mut conn_ref = self.world.ecs_world.write_resource::<ConnectionCollection>();
-        ::std::mem::swap(&mut *conn_ref, &mut self.world.connections);
+        *conn_ref = self.world.connections.clone();
         drop(conn_ref);
 
         match instruction {
@@ -146,15 +272,28 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
                 run_dispatcher
             } => {
                 if run_dispatcher {
-                    let inputs = self.get_inputs();
+                    self.world.connections.pop_new_keys();
+                    let (inputs, acks) = self.get_inputs();
                     self.world.ecs_world.add_resource(inputs);
+                    self.world.ecs_world.add_resource(acks);
                     self.world.system_executor.run(&mut self.world.ecs_world);
-                    self.world.ecs_world.maintain();
                 }
             }
             _ => {}
         }
 
+        // Hand this tick's events to the master controller
+        let mut messages_ref = self.world.ecs_world.write_resource::<Messages<E>>();
+        let mut events = Messages::<E>::new();
+        ::std::mem::swap(&mut *messages_ref, &mut events);
+        drop(messages_ref);
+        self.next_instruction = self.states.active().on_events(&mut self.world, &events);
+
+        let transition = self.states.active().transition(&mut self.world);
+        if let Err(e) = self.states.apply(transition, &mut self.world) {
+            eprintln!("{}", e);
+        }
+
         // Get views
         let mut view_ref = self.world.ecs_world.write_resource::<ViewMap>();
         let mut views = ViewMap::new();
@@ -162,7 +301,11 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> Engine<'a, 'b, E> {
         ::std::mem::swap(&mut *view_ref, &mut views);
         drop(view_ref);
         // Send views through view channels
-        for (key, view) in views {
+        let acks = self.world.ecs_world.read_resource::<InputAcks>().clone();
+        for (key, mut view) in views {
+            view.tick = stamp.0;
+            view.time = stamp.1;
+            view.last_input = acks.get(&key);
             match self.view_channels.get_mut(&key) {
                 Some(channel) => {
                     match channel.send(view) {
@@ -196,14 +339,129 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> EngineBuilder<'a, 'b, E> {
         self.server_conf.port = port;
         self
     }
+
+    /// How far behind the server clock clients should render, told to them in every pong.
+    pub fn with_interpolation_delay(mut self, seconds: f64) -> Self {
+        self.server_conf.interpolation_delay = seconds;
+        self
+    }
     
+    /// Systems added after this go into `stage`, see `Stage`.
+    pub fn in_stage(mut self, stage: Stage) -> Self {
+        self.system_executor_builder.set_stage(stage);
+        self
+    }
+
     pub fn with_system<S>(mut self, system: S, name: &str, dep: &[&str]) -> Self
     where
         S: for<'c> specs::System<'c> + Send + 'a {
         self.system_executor_builder.add_system(system, name, dep);
         self
     }
+
+    /// Adds a system that only runs while `group` is enabled, see `World::enable_system_group`.
+    pub fn with_grouped_system<S>(mut self, system: S, name: &str, dep: &[&str], group: &str) -> Self
+    where
+        S: for<'c> specs::System<'c> + Send + 'a {
+        self.system_executor_builder.add_grouped_system(system, name, dep, group);
+        self
+    }
+
+    /// Adds a system that only runs on ticks where `criterion` holds for the resource `R`.
+    pub fn with_system_run_if<S, R, F>(mut self, system: S, name: &str, dep: &[&str], criterion: F) -> Self
+    where
+        S: for<'c> specs::System<'c> + Send + 'a,
+        R: Send + Sync + 'static,
+        F: Fn(&R) -> bool + 'static {
+        self.system_executor_builder.add_system_run_if(system, name, dep, criterion);
+        self
+    }
+
+    /// Adds a system that runs on the engine thread, so it doesn't have to be `Send`.
+    pub fn with_thread_local<S>(mut self, system: S) -> Self
+    where
+        S: for<'c> specs::System<'c> + 'b {
+        self.system_executor_builder.add_thread_local(system);
+        self
+    }
+
+    pub fn with_barrier(mut self) -> Self {
+        self.system_executor_builder.add_barrier();
+        self
+    }
+
+    /// Keeps a `SpatialIndex` of every positioned entity, updated by a system named
+    /// "spatial_index" in `Stage::PostUpdate`. `ViewSystem` uses it to cull camera views.
+    pub fn with_spatial_index(mut self, cell_size: f32) -> Self {
+        if self.spatial_cell_size.is_none() {
+            let stage = self.system_executor_builder.stage();
+            self.system_executor_builder.set_stage(Stage::PostUpdate);
+            self.system_executor_builder.add_system(SpatialIndexSystem::new(), "spatial_index", &[]);
+            self.system_executor_builder.set_stage(stage);
+        }
+        self.spatial_cell_size = Some(cell_size);
+        self
+    }
     
+    /// Adds a tile map, and sends clients the chunks within `sync_radius` chunks of their
+    /// camera, see `TileSyncSystem`. Entities on the map can be given a `PathRequest`.
+    pub fn with_tile_map(mut self, map: TileMap, sync_radius: u32) -> Self {
+        self.tile_map = Some((map, sync_radius));
+        self
+    }
+
+    /// Sets the size of a tile in world units, and moves `GridMover`s and keeps `GridSync`
+    /// positions up to date at the end of the Update stage.
+    pub fn with_grid(mut self, tile_width: f32, tile_height: f32) -> Self {
+        self.grid = Some(GridConfig::new(tile_width, tile_height));
+        self
+    }
+
+    /// Runs at most `hz` ticks a second: `tick` sleeps until the next tick is due. This also
+    /// sets `ServerTime::fixed_delta`, which is otherwise based on `DEFAULT_TICK_RATE`.
+    pub fn with_tick_rate(mut self, hz: f64) -> Self {
+        self.tick_rate = Some(hz);
+        self
+    }
+
+    /// Moves entities by their `Velocity` and resolves `Collider`s at the end of the Update
+    /// stage, see `PhysicsSystem`. Collisions are published in `CollisionEvents`.
+    pub fn with_physics(mut self, config: PhysicsConfig) -> Self {
+        self.physics = Some(config);
+        self
+    }
+
+    /// Turns each connection's `InputState` into `ActionStates` in `Stage::Engine` every tick,
+    /// using `map` unless it's replaced in the world, see `ActionSystem`.
+    pub fn with_action_map<A>(mut self, map: ActionMap<A>) -> Self
+    where
+        A: Clone + Eq + Hash + Send + Sync + 'static {
+        let stage = self.system_executor_builder.stage();
+        self.system_executor_builder.set_stage(Stage::Engine);
+        self.system_executor_builder.add_system(ActionSystem::new(map), "actions", &["input_state"]);
+        self.system_executor_builder.set_stage(stage);
+        self
+    }
+
+    /// Reports entities entering, staying in and leaving `TriggerZone`s in `TriggerEvents`,
+    /// once everything has moved for the tick, see `TriggerSystem`. This keeps a
+    /// `SpatialIndex` too, with 64 unit cells unless `with_spatial_index` sets another size.
+    pub fn with_triggers(mut self) -> Self {
+        self.triggers = true;
+        self
+    }
+
+    /// Lights the area in `config` from every `LightSource`, and sends clients the light
+    /// around their camera, see `LightingSystem` and `LightSyncSystem`.
+    pub fn with_lighting(mut self, config: LightingConfig) -> Self {
+        let stage = self.system_executor_builder.stage();
+        self.system_executor_builder.set_stage(Stage::PostUpdate);
+        self.system_executor_builder.add_system(LightingSystem::new(), "lighting", &[]);
+        self.system_executor_builder.set_stage(stage);
+        self.lighting = Some(config);
+        self
+    }
+
     pub fn with_mc<M: 'static>(mut self, master_controller: M) -> Self
     where
         M: MasterController<ObserverEvent=E> {
@@ -216,22 +474,93 @@ impl<'a, 'b, E: Sync + Send + Clone + 'static> EngineBuilder<'a, 'b, E> {
     }
     
     pub fn build(mut self) -> Option<Engine<'a, 'b, E>> {
+        // Animations move on once the game's own systems have picked them for the tick
+        self.system_executor_builder.set_stage(Stage::PostUpdate);
+        self.system_executor_builder.add_system(AnimationSystem, "animation", &[]);
+
+        // Triggers look entities up once the index has this tick's positions
+        if self.triggers {
+            if self.spatial_cell_size.is_none() {
+                self = self.with_spatial_index(SpatialIndex::default().cell_size());
+            }
+            self.system_executor_builder.set_stage(Stage::PostUpdate);
+            self.system_executor_builder.add_system(TriggerSystem::new(), "triggers", &["spatial_index"]);
+        }
+
+        // Ids are handed out after every other PostUpdate system, so entities created up to
+        // that point are in this tick's views
+        self.system_executor_builder.add_thread_local(NetworkIdSystem);
+
+        // Physics and grid movement go after a barrier at the end of Update, so velocities
+        // the game's own systems set and steps they queue take effect on the same tick
+        if self.physics.is_some() || self.grid.is_some() {
+            self.system_executor_builder.set_stage(Stage::Update);
+            self.system_executor_builder.add_barrier();
+        }
+        if self.physics.is_some() {
+            self.system_executor_builder.add_system(PhysicsSystem::new(), "physics", &[]);
+        }
+        if self.grid.is_some() {
+            let after_physics: &[&str] = if self.physics.is_some() { &["physics"] } else { &[] };
+            self.system_executor_builder.add_system(GridMovementSystem, "grid_movement", after_physics);
+            self.system_executor_builder.add_system(GridSyncSystem, "grid_sync", &["grid_movement"]);
+        }
+
+        // Tiles are added to views once every other view system is done
+        let tile_map = match self.tile_map.take() {
+            Some((map, radius)) => {
+                // Paths found last tick are ready before any Update system moves along them
+                self.system_executor_builder.set_stage(Stage::PreUpdate);
+                self.system_executor_builder.add_system(PathfindingSystem::new(), "pathfinding", &[]);
+                self.system_executor_builder.set_stage(Stage::View);
+                self.system_executor_builder.add_thread_local(TileSyncSystem::new(radius));
+                Some(map)
+            },
+            None => None
+        };
+        if self.lighting.is_some() {
+            self.system_executor_builder.set_stage(Stage::View);
+            self.system_executor_builder.add_thread_local(LightSyncSystem::new());
+        }
+
         let mut engine = Engine {
             world: World {
                 system_executor: self.system_executor_builder.build(),
                 ecs_world: specs::prelude::World::new(),
                 connections: ConnectionCollection::new(),
             },
-            master_controller: self.master_controller?,
+            states: StateStack::new(self.master_controller?),
             server_conf: self.server_conf,
             input_buffer: None,
             prev_time: Instant::now(),
+            start_time: Instant::now(),
             // This is a fake channel
             connection_channel: channel().1,
             view_channels: HashMap::new(),
-            server_stream_handler: self.server_stream_handler
+            server_stream_handler: self.server_stream_handler,
+            next_instruction: None,
+            tick_interval: self.tick_rate.map(|hz| Duration::from_float_secs(1.0 / hz))
         };
         engine.init_resources();
+        if let Some(cell_size) = self.spatial_cell_size {
+            engine.world.ecs_world.add_resource(SpatialIndex::new(cell_size));
+        }
+        if let Some(map) = tile_map {
+            engine.world.ecs_world.add_resource(map);
+        }
+        if let Some(config) = self.grid {
+            engine.world.ecs_world.add_resource(config);
+        }
+        if let Some(config) = self.physics {
+            engine.world.ecs_world.add_resource(config);
+        }
+        if let Some(hz) = self.tick_rate {
+            engine.world.ecs_world.write_resource::<ServerTime>().fixed_delta = 1.0 / hz;
+        }
+        if let Some(config) = self.lighting {
+            engine.world.ecs_world.add_resource(config);
+        }
+        engine.world.system_executor.setup(&mut engine.world.ecs_world);
         Some(engine)
     }
 }
\ No newline at end of file
@@ -2,26 +2,30 @@ use std::net::{TcpListener, TcpStream};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread::{JoinHandle, spawn, sleep};
-use super::world::{Input, Connection, ClientView};
+use super::world::{Input, SequencedInput, Connection, ClientView, InputAcks};
 use std::collections::{HashMap, VecDeque};
 use bytes::{BytesMut, BufMut};
 use std::ops::{Deref, DerefMut};
 use std::io::{Read, Write};
 use std::sync::mpsc::{Sender, channel, Receiver};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use crate::utils::server::*;
 use crate::utils::StreamHandler;
+use crate::assets::AssetRegistry;
 
 pub(crate) type InputBufferMutex = Arc<Mutex<PlayerInputBuffer>>;
 
 pub(crate) struct PlayerInputBuffer {
-    inner: HashMap<String, VecDeque<Input>>
+    inner: HashMap<String, VecDeque<SequencedInput>>,
+    // Messages are acknowledged even if they carried no inputs
+    acks: InputAcks
 }
 
 #[derive(Clone)]
 pub struct StreamData {
     login_key: String,
-    should_connect: bool
+    should_connect: bool,
+    cached_manifest: Option<String>
 }
 
 impl StreamData {
@@ -36,23 +40,33 @@ impl StreamData {
     pub fn do_connect(login_key: String) -> Self {
         StreamData {
             login_key,
-            should_connect: true
+            should_connect: true,
+            cached_manifest: None
         }
     }
 
     pub fn do_connect_str(login_key: &str) -> Self {
         StreamData {
             login_key: login_key.to_string(),
-            should_connect: true
+            should_connect: true,
+            cached_manifest: None
         }
     }
 
     pub fn dont_connect() -> Self {
         StreamData {
             login_key: "".to_string(),
-            should_connect: false
+            should_connect: false,
+            cached_manifest: None
         }
     }
+
+    /// Tells the server the client has the asset manifest with this hash cached, so it only
+    /// needs to be sent if it has changed.
+    pub fn with_cached_manifest(mut self, hash: &str) -> Self {
+        self.cached_manifest = Some(hash.to_string());
+        self
+    }
 }
 
 
@@ -61,22 +75,42 @@ pub(crate) struct Server {
     tcp_listener: TcpListener,
     input_stream: InputBufferMutex,
     connection_channel: Sender<(Connection, Sender<ClientView>)>,
+    clock: ServerClock,
+    assets: Option<Arc<AssetRegistry>>
 }
 
 #[derive(Clone)]
 pub(crate) struct ServerConfig {
     pub port: u16,
-    pub server_name: String
+    pub server_name: String,
+    pub interpolation_delay: f64,
+    pub assets: Option<Arc<AssetRegistry>>
+}
+
+// Lets client threads answer pings with the same clock the engine stamps views with.
+#[derive(Clone)]
+pub(crate) struct ServerClock {
+    start: Instant,
+    interpolation_delay: f64
 }
 
 impl PlayerInputBuffer {
     pub fn new() -> Self {
         PlayerInputBuffer {
-            inner: HashMap::new()
+            inner: HashMap::new(),
+            acks: InputAcks::new()
         }
     }
 
-    pub fn push_input(&mut self, player: String, input: Input) {
+    pub fn acks(&self) -> &InputAcks {
+        &self.acks
+    }
+
+    pub fn acknowledge(&mut self, player: String, seq: u64) {
+        self.acks.acknowledge(player, seq);
+    }
+
+    pub fn push_input(&mut self, player: String, input: SequencedInput) {
         if let Some(mut input_v) = self.inner.get_mut(&player) {
             input_v.push_back(input);
         } else {
@@ -87,7 +121,7 @@ impl PlayerInputBuffer {
         }
     }
 
-    pub fn pop_input(&mut self, player: String) -> Option<Input> {
+    pub fn pop_input(&mut self, player: String) -> Option<SequencedInput> {
         if let Some(mut input_v) = self.inner.get_mut(&player) {
             input_v.pop_front()
         } else {
@@ -100,18 +134,31 @@ impl ServerConfig {
     pub fn new() -> Self {
         ServerConfig {
             port: 1212, // the default port for Hyperspeed
-            server_name: "default_name".to_string()
+            server_name: "default_name".to_string(),
+            interpolation_delay: 0.1,
+            assets: None
         }
     }
 }
 
+impl ServerClock {
+    pub(crate) fn now(&self) -> f64 {
+        self.start.elapsed().as_float_secs()
+    }
+}
+
 impl Server {
-    pub(crate) fn new(s: ServerConfig, c_sender: Sender<(Connection, Sender<ClientView>)>, stream_handler: StreamHandler) -> Server {
+    pub(crate) fn new(s: ServerConfig, c_sender: Sender<(Connection, Sender<ClientView>)>, stream_handler: StreamHandler, start: Instant) -> Server {
         Server {
             tcp_listener: TcpListener::bind(format!("0.0.0.0:{}", s.port)).unwrap(),
             input_stream: Arc::new(Mutex::new(PlayerInputBuffer::new())),
             connection_channel: c_sender,
-            stream_handle: stream_handler
+            stream_handle: stream_handler,
+            clock: ServerClock {
+                start,
+                interpolation_delay: s.interpolation_delay
+            },
+            assets: s.assets
         }
     }
     pub(crate) fn main_loop(&mut self) {
@@ -121,14 +168,23 @@ impl Server {
             match data {
                 StreamData {
                     login_key,
-                    should_connect
+                    should_connect,
+                    cached_manifest
                 } => {
                     if should_connect {
                         let mutex_clone = self.input_stream.clone();
                         let (send, recv) = channel();
                         let conn = Connection { key: login_key.clone() };
+                        let clock = self.clock.clone();
+                        let manifest = self.assets.as_ref().map(|assets| {
+                            let cached = cached_manifest.as_ref().map(|hash| hash.as_str()) == Some(assets.hash());
+                            ServerMessage::Manifest {
+                                hash: assets.hash().to_string(),
+                                manifest: if cached { None } else { Some(assets.manifest().clone()) }
+                            }
+                        });
                         self.connection_channel.send((conn, send));
-                        spawn(move || stream_communicate(stream, recv, mutex_clone, login_key));
+                        spawn(move || stream_communicate(stream, recv, mutex_clone, login_key, clock, manifest));
                     }
                 }
             }
@@ -140,7 +196,7 @@ impl Server {
 }
 
 impl Deref for PlayerInputBuffer {
-    type Target = HashMap<String, VecDeque<Input>>;
+    type Target = HashMap<String, VecDeque<SequencedInput>>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
@@ -153,9 +209,12 @@ impl DerefMut for PlayerInputBuffer {
     }
 }
 
-fn put_buffer(input_buffer: &mut InputBufferMutex, player: String, input: Input) {
+fn put_buffer(input_buffer: &mut InputBufferMutex, player: String, seq: u64, inputs: Vec<Input>) {
     let mut lock = input_buffer.lock().unwrap();
-    lock.push_input(player, input);
+    for input in inputs {
+        lock.push_input(player.clone(), SequencedInput { seq, input });
+    }
+    lock.acknowledge(player, seq);
     drop(lock);
 }
 
@@ -170,20 +229,24 @@ fn get_new_view(receiver: &mut Receiver<ClientView>) -> Option<ClientView> {
 }
 
 const BUFFER_SIZE: usize = 512;
-fn stream_communicate(mut stream: TcpStream, mut view_channel: Receiver<ClientView>, mut input_m: InputBufferMutex, key: String) {
+fn stream_communicate(mut stream: TcpStream, mut view_channel: Receiver<ClientView>, mut input_m: InputBufferMutex, key: String, clock: ServerClock, manifest: Option<ServerMessage>) {
     println!("Connection made!");
     let mut buffer = BytesMut::with_capacity(BUFFER_SIZE);
     buffer.put(&[0; BUFFER_SIZE][..]);
     stream.set_nonblocking(true);
+    if let Some(manifest) = manifest {
+        send_message_to_stream(&mut stream, &manifest);
+    }
     loop {
         // send new data to the client
         let mut view = get_new_view(&mut view_channel);
         if view.is_some() {
             // Get latest view
             loop {
-                let mut tmp = get_new_view(&mut view_channel);
-                if tmp.is_some() {
-                    view = tmp;
+                let tmp = get_new_view(&mut view_channel);
+                if let Some(mut newer) = tmp {
+                    newer.absorb(view.unwrap());
+                    view = Some(newer);
                 } else {
                     break;
                 }
@@ -194,7 +257,11 @@ fn stream_communicate(mut stream: TcpStream, mut view_channel: Receiver<ClientVi
 
         use self::StreamReadResult::*;
         match read_from_message_from_stream_nonblocking(&mut stream, &mut buffer) {
-            ValidMessage(s) => handle_msg(s, &mut input_m),
+            ValidMessage(s) => {
+                if let Some(reply) = handle_msg(s, &mut input_m, &key, &clock) {
+                    send_message_to_stream(&mut stream, &reply);
+                }
+            },
             InvalidMessage => println!("Invalid message from client!"),
             NotReady => continue,
             StreamError(e) => {
@@ -205,16 +272,31 @@ fn stream_communicate(mut stream: TcpStream, mut view_channel: Receiver<ClientVi
     }
 }
 
-fn handle_msg(msg: String, mut input_m: &mut InputBufferMutex) {
+fn handle_msg(msg: String, mut input_m: &mut InputBufferMutex, key: &String, clock: &ServerClock) -> Option<ServerMessage> {
     println!("{}", msg);
     let msg = serde_json::from_str(msg.as_str());
     match msg {
-        Ok(InputMessage {
+        Ok(ClientMessage::Ping { ping }) => {
+            Some(ServerMessage::Pong {
+                client_time: ping,
+                server_time: clock.now(),
+                interpolation_delay: clock.interpolation_delay
+            })
+        },
+        Ok(ClientMessage::Input(InputMessage {
+                seq,
                 clicks,
-            keys
-             }) => {
-            println!("{:?}", clicks);
+            keys,
+            events
+             })) => {
+            let inputs = keys.into_iter()
+                .map(|c| Input::Key(c.to_string()))
+                .chain(clicks.into_iter().map(|(x, y)| Input::Click { x, y }))
+                .chain(events)
+                .collect();
+            put_buffer(&mut input_m, key.clone(), seq, inputs);
+            None
         },
-        Err(_) => ()
+        Err(_) => None
     }
 }
@@ -0,0 +1,361 @@
+use super::{InputState, MouseButton};
+use crate::utils::*;
+use serde::de::DeserializeOwned;
+use specs::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::hash::Hash;
+
+/// An input that can be bound to an action.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(String),
+    Mouse(MouseButton),
+    /// A button on any gamepad.
+    GamepadButton(String)
+}
+
+impl Binding {
+    /// "click" or "mouse:left", "mouse:right" and "mouse:middle" are mouse buttons,
+    /// "gamepad:<button>" is a gamepad button, and anything else is the name of a key.
+    pub fn parse(name: &str) -> Self {
+        match name {
+            "click" | "mouse:left" => Binding::Mouse(MouseButton::Left),
+            "mouse:right" => Binding::Mouse(MouseButton::Right),
+            "mouse:middle" => Binding::Mouse(MouseButton::Middle),
+            _ if name.starts_with("gamepad:") => Binding::GamepadButton(name["gamepad:".len()..].to_string()),
+            key => Binding::Key(key.to_string())
+        }
+    }
+
+    /// Whether the input is held down or was pressed this tick.
+    pub fn is_active(&self, state: &InputState) -> bool {
+        match *self {
+            Binding::Key(ref key) => state.is_key_down(key) || state.was_key_pressed(key),
+            Binding::Mouse(button) => state.is_button_down(button) || state.was_button_pressed(button),
+            Binding::GamepadButton(ref button) => state.any_gamepad_button(button)
+        }
+    }
+
+    /// Whether the input was pressed this tick, rather than held down from an earlier one.
+    pub fn was_pressed(&self, state: &InputState) -> bool {
+        match *self {
+            Binding::Key(ref key) => state.was_key_pressed(key),
+            Binding::Mouse(button) => state.was_button_pressed(button),
+            Binding::GamepadButton(ref button) => state.any_gamepad_button_pressed(button)
+        }
+    }
+}
+
+// Binds inputs to a game's actions, which can be strings or the game's own event type. Maps
+// are loaded from JSON of bindings to actions, like
+//
+// { "w": "Up", "ArrowUp": "Up", "gamepad:DPadUp": "Up", "click": "Fire", "mouse:right": "Aim" }
+//
+// where each action is deserialized as `A`. Players can rebind inputs for themselves without
+// changing anyone else's bindings.
+#[derive(Clone, Debug)]
+pub struct ActionMap<A> {
+    bindings: HashMap<Binding, A>,
+    // `None` unbinds an input for that player
+    players: HashMap<String, HashMap<Binding, Option<A>>>
+}
+
+impl<A> Default for ActionMap<A> {
+    fn default() -> Self {
+        ActionMap {
+            bindings: HashMap::new(),
+            players: HashMap::new()
+        }
+    }
+}
+
+impl<A: Clone> ActionMap<A> {
+    pub fn new() -> Self {
+        ActionMap::default()
+    }
+
+    pub fn load_str(json: &str) -> Result<Self, String>
+    where
+        A: DeserializeOwned {
+        let bindings: HashMap<String, A> = serde_json::from_str(json)
+            .map_err(|e| format!("Action map could not be parsed: {}", e))?;
+        let mut map = ActionMap::new();
+        for (name, action) in bindings {
+            map.bind(Binding::parse(&name), action);
+        }
+        Ok(map)
+    }
+
+    pub fn load_file(path: &str) -> Result<Self, String>
+    where
+        A: DeserializeOwned {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Action map {} could not be read: {}", path, e))?;
+        ActionMap::load_str(&json)
+    }
+
+    pub fn bind(&mut self, binding: Binding, action: A) {
+        self.bindings.insert(binding, action);
+    }
+
+    pub fn unbind(&mut self, binding: &Binding) {
+        self.bindings.remove(binding);
+    }
+
+    /// Binds an input to an action for one player only.
+    pub fn bind_for(&mut self, player: &str, binding: Binding, action: A) {
+        self.players.entry(player.to_string()).or_insert_with(HashMap::new).insert(binding, Some(action));
+    }
+
+    /// Stops an input doing anything for one player, even if it's bound for everyone else.
+    pub fn unbind_for(&mut self, player: &str, binding: Binding) {
+        self.players.entry(player.to_string()).or_insert_with(HashMap::new).insert(binding, None);
+    }
+
+    /// Drops a player's own bindings, so they get the same ones as everyone else.
+    pub fn reset_player(&mut self, player: &str) {
+        self.players.remove(player);
+    }
+
+    pub fn action_for(&self, player: &str, binding: &Binding) -> Option<&A> {
+        match self.players.get(player).and_then(|bindings| bindings.get(binding)) {
+            Some(action) => action.as_ref(),
+            None => self.bindings.get(binding)
+        }
+    }
+
+    /// Every input bound for the player, with their own bindings in place of everyone else's.
+    pub fn bindings_for(&self, player: &str) -> Vec<(&Binding, &A)> {
+        let own = self.players.get(player);
+        let shared = self.bindings.iter()
+            .filter(|(binding, _)| own.map(|own| !own.contains_key(*binding)).unwrap_or(true));
+        let own = own.into_iter().flatten()
+            .filter_map(|(binding, action)| action.as_ref().map(|action| (binding, action)));
+        shared.chain(own).collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ActionState {
+    /// Started this tick.
+    Pressed,
+    /// Started on an earlier tick and still going.
+    Held,
+    /// Stopped this tick.
+    Released
+}
+
+/// The state of every action that's going or just stopped, for each connection, worked out
+/// by `ActionSystem` each tick.
+#[derive(Clone, Debug)]
+pub struct ActionStates<A: Eq + Hash> {
+    players: HashMap<String, HashMap<A, ActionState>>
+}
+
+impl<A: Eq + Hash> Default for ActionStates<A> {
+    fn default() -> Self {
+        ActionStates {
+            players: HashMap::new()
+        }
+    }
+}
+
+impl<A: Eq + Hash> ActionStates<A> {
+    pub fn new() -> Self {
+        ActionStates::default()
+    }
+
+    pub fn state(&self, player: &str, action: &A) -> Option<ActionState> {
+        self.players.get(player).and_then(|actions| actions.get(action)).cloned()
+    }
+
+    pub fn is_pressed(&self, player: &str, action: &A) -> bool {
+        self.state(player, action) == Some(ActionState::Pressed)
+    }
+
+    /// Whether the action was pressed this tick or is being held.
+    pub fn is_down(&self, player: &str, action: &A) -> bool {
+        match self.state(player, action) {
+            Some(ActionState::Pressed) | Some(ActionState::Held) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_released(&self, player: &str, action: &A) -> bool {
+        self.state(player, action) == Some(ActionState::Released)
+    }
+
+    pub fn actions(&self, player: &str) -> Vec<(&A, ActionState)> {
+        self.players.get(player)
+            .map(|actions| actions.iter().map(|(action, state)| (action, *state)).collect())
+            .unwrap_or_else(Vec::new)
+    }
+}
+
+// Turns each connection's `InputState` into `ActionStates` using the `ActionMap`. An action
+// is held for as long as an input bound to it is down, and released on the first tick none is.
+// A key or click sent without a release is pressed and let go on the same tick, so tapping it
+// on two ticks in a row presses the action twice instead of holding it.
+pub struct ActionSystem<A> {
+    // Added as a resource in `setup`
+    map: Option<ActionMap<A>>
+}
+
+impl<A> ActionSystem<A> {
+    pub fn new(map: ActionMap<A>) -> Self {
+        ActionSystem {
+            map: Some(map)
+        }
+    }
+}
+
+impl<'a, A: Clone + Eq + Hash + Send + Sync + 'static> System<'a> for ActionSystem<A> {
+    type SystemData = (ReadInputStates<'a>,
+    ReadConnections<'a>,
+    Read<'a, ActionMap<A>>,
+    Write<'a, ActionStates<A>>);
+
+    fn run(&mut self, (inputs, connections, map, mut states): Self::SystemData) {
+        let mut previous = ::std::mem::replace(&mut states.players, HashMap::new());
+
+        for conn in &connections.connections {
+            let key = &conn.key;
+            // Actions with an input still down from last tick, and ones with an input pressed
+            // this tick
+            let mut held = HashSet::new();
+            let mut pressed = HashSet::new();
+            if let Some(input) = inputs.get(key) {
+                for (binding, action) in map.bindings_for(key) {
+                    if binding.was_pressed(input) {
+                        pressed.insert(action.clone());
+                    } else if binding.is_active(input) {
+                        held.insert(action.clone());
+                    }
+                }
+            }
+            let before = previous.remove(key).unwrap_or_else(HashMap::new);
+
+            let mut actions = HashMap::new();
+            for (action, state) in before {
+                if state == ActionState::Released {
+                    continue;
+                }
+                let state = if held.contains(&action) {
+                    ActionState::Held
+                } else if pressed.contains(&action) {
+                    ActionState::Pressed
+                } else {
+                    ActionState::Released
+                };
+                actions.insert(action, state);
+            }
+            for action in pressed.into_iter().chain(held) {
+                actions.entry(action).or_insert(ActionState::Pressed);
+            }
+            states.players.insert(key.clone(), actions);
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        if let Some(map) = self.map.take() {
+            res.insert(map);
+        }
+        <Self::SystemData as SystemData>::setup(res);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Connection, ConnectionCollection, Input, InputStateSystem, InputStates, Modifiers, SequencedInput};
+
+    struct Game {
+        world: World,
+        actions: ActionSystem<String>
+    }
+
+    impl Game {
+        fn new(map: ActionMap<String>) -> Self {
+            let mut world = World::new();
+            let mut connections = ConnectionCollection::new();
+            connections.push(Connection { key: "p1".to_string() });
+            connections.push(Connection { key: "p2".to_string() });
+            world.add_resource(connections);
+            world.add_resource(InputStates::new());
+            let mut actions = ActionSystem::new(map);
+            System::setup(&mut actions, &mut world.res);
+            Game { world, actions }
+        }
+
+        fn tick(&mut self, inputs: Vec<(&str, Input)>) {
+            let mut map = InputMap::new();
+            for (player, input) in inputs {
+                map.entry(player.to_string()).or_insert_with(Default::default).push_back(SequencedInput { seq: 0, input });
+            }
+            self.world.add_resource(map);
+            InputStateSystem.run_now(&self.world.res);
+            self.actions.run_now(&self.world.res);
+        }
+
+        fn state(&self, player: &str, action: &str) -> Option<ActionState> {
+            self.world.read_resource::<ActionStates<String>>().state(player, &action.to_string())
+        }
+    }
+
+    fn down(key: &str) -> Input {
+        Input::KeyDown { key: key.to_string(), modifiers: Modifiers::default() }
+    }
+
+    fn up(key: &str) -> Input {
+        Input::KeyUp { key: key.to_string(), modifiers: Modifiers::default() }
+    }
+
+    fn map() -> ActionMap<String> {
+        ActionMap::load_str(r#"{ "w": "Up", "ArrowUp": "Up", "click": "Fire" }"#).unwrap()
+    }
+
+    #[test]
+    fn actions_are_pressed_held_and_released() {
+        let mut game = Game::new(map());
+        game.tick(vec!(("p1", down("w"))));
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Pressed));
+        game.tick(vec!());
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Held));
+        game.tick(vec!());
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Held));
+        // A second input for the same action doesn't press it again
+        game.tick(vec!(("p1", down("ArrowUp"))));
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Held));
+        game.tick(vec!(("p1", up("w")), ("p1", up("ArrowUp"))));
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Released));
+        game.tick(vec!());
+        assert_eq!(game.state("p1", "Up"), None);
+        assert_eq!(game.state("p2", "Up"), None);
+    }
+
+    #[test]
+    fn taps_on_consecutive_ticks_press_twice() {
+        let mut game = Game::new(map());
+        game.tick(vec!(("p1", Input::Click { x: 0, y: 0 })));
+        assert_eq!(game.state("p1", "Fire"), Some(ActionState::Pressed));
+        game.tick(vec!(("p1", Input::Click { x: 0, y: 0 })));
+        assert_eq!(game.state("p1", "Fire"), Some(ActionState::Pressed));
+        game.tick(vec!());
+        assert_eq!(game.state("p1", "Fire"), Some(ActionState::Released));
+    }
+
+    #[test]
+    fn rebinding_only_changes_one_player() {
+        let mut map = map();
+        map.bind_for("p2", Binding::parse("e"), "Up".to_string());
+        map.unbind_for("p2", Binding::parse("w"));
+        let mut game = Game::new(map);
+        game.tick(vec!(("p1", down("w")), ("p2", down("w"))));
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Pressed));
+        assert_eq!(game.state("p2", "Up"), None);
+        game.tick(vec!(("p1", down("e")), ("p2", down("e"))));
+        assert_eq!(game.state("p1", "Up"), Some(ActionState::Held));
+        assert_eq!(game.state("p2", "Up"), Some(ActionState::Pressed));
+    }
+}
@@ -1,5 +1,295 @@
-use specs::{Entity, World};
+use specs::{Component, Entity, World, LazyUpdate};
+use specs::world::EntitiesRes;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fs;
 
+/// Anything that can build a new entity in a world.
 pub trait Blueprint {
-    fn add_to_world(mut self, _w: &mut World);
-}
\ No newline at end of file
+    fn add_to_world(self, w: &mut World) -> Entity;
+}
+
+type Inserter = Box<FnOnce(Entity, &LazyUpdate)>;
+
+type ComponentLoader = Box<Fn(&Value) -> Result<Inserter, String> + Send + Sync>;
+
+#[derive(Clone, Debug, Deserialize)]
+struct BlueprintDef {
+    #[serde(default)]
+    extends: Option<String>,
+    #[serde(default)]
+    components: Map<String, Value>
+}
+
+/// A blueprint whose inheritance has been resolved and whose components have all been
+/// parsed, so spawning it can't fail.
+pub struct Prefab {
+    components: Vec<Inserter>
+}
+
+// Blueprints are loaded from JSON of the form
+//
+// {
+//     "actor": { "components": { "Position": { "x": 0.0, "y": 0.0 } } },
+//     "player": { "extends": "actor", "components": { "Visible": { "sprite": 1 } } }
+// }
+//
+// Components are looked up by the name they were registered under. A child's fields are
+// merged over its parent's, and a component set to null is removed from the child.
+#[derive(Default)]
+pub struct BlueprintRegistry {
+    loaders: HashMap<String, ComponentLoader>,
+    blueprints: HashMap<String, BlueprintDef>
+}
+
+impl Prefab {
+    /// Creates the entity straight away; its components are inserted on the next `maintain`.
+    pub fn build(self, entities: &EntitiesRes, lazy: &LazyUpdate) -> Entity {
+        let entity = entities.create();
+        for insert in self.components {
+            insert(entity, lazy);
+        }
+        entity
+    }
+}
+
+// Like `build`, the components show up when the world is next maintained, which is at the
+// end of the stage that spawned the entity.
+impl Blueprint for Prefab {
+    fn add_to_world(self, w: &mut World) -> Entity {
+        let entities = w.entities();
+        let lazy = w.read_resource::<LazyUpdate>();
+        self.build(&entities, &lazy)
+    }
+}
+
+impl BlueprintRegistry {
+    pub fn new() -> Self {
+        BlueprintRegistry {
+            loaders: HashMap::new(),
+            blueprints: HashMap::new()
+        }
+    }
+
+    /// Lets blueprints use `T` under the given name.
+    pub fn register_component<T>(&mut self, name: &str)
+    where T: Component + DeserializeOwned + Send + Sync {
+        self.loaders.insert(name.to_string(), Box::new(|value: &Value| {
+            let component: T = serde_json::from_value(value.clone())
+                .map_err(|e| e.to_string())?;
+            Ok(Box::new(move |entity: Entity, lazy: &LazyUpdate| lazy.insert(entity, component)) as Inserter)
+        }));
+    }
+
+    pub fn load_str(&mut self, json: &str) -> Result<(), String> {
+        let defs: HashMap<String, BlueprintDef> = serde_json::from_str(json)
+            .map_err(|e| format!("Blueprints could not be parsed: {}", e))?;
+        self.blueprints.extend(defs);
+        Ok(())
+    }
+
+    pub fn load_file(&mut self, path: &str) -> Result<(), String> {
+        let json = fs::read_to_string(path)
+            .map_err(|e| format!("Blueprint file {} could not be read: {}", path, e))?;
+        self.load_str(&json)
+    }
+
+    pub fn has_blueprint(&self, name: &str) -> bool {
+        self.blueprints.contains_key(name)
+    }
+
+    pub fn prefab(&self, name: &str) -> Result<Prefab, String> {
+        self.prefab_with(name, &Value::Null)
+    }
+
+    /// Like `prefab`, but merges `overrides` (a map of component names to fields) over the
+    /// blueprint first.
+    pub fn prefab_with(&self, name: &str, overrides: &Value) -> Result<Prefab, String> {
+        let mut components = self.resolve(name, &mut vec!())?;
+        if let Value::Object(ref overrides) = *overrides {
+            merge_components(&mut components, overrides);
+        }
+
+        let mut inserters = vec!();
+        for (component, value) in &components {
+            let loader = self.loaders.get(component)
+                .ok_or_else(|| format!("Blueprint {} uses unregistered component {}", name, component))?;
+            inserters.push(loader(value)
+                .map_err(|e| format!("Component {} of blueprint {} is invalid: {}", component, name, e))?);
+        }
+        Ok(Prefab {
+            components: inserters
+        })
+    }
+
+    pub fn spawn(&self, name: &str, entities: &EntitiesRes, lazy: &LazyUpdate) -> Result<Entity, String> {
+        Ok(self.prefab(name)?.build(entities, lazy))
+    }
+
+    pub fn spawn_with(&self, name: &str, overrides: &Value, entities: &EntitiesRes, lazy: &LazyUpdate) -> Result<Entity, String> {
+        Ok(self.prefab_with(name, overrides)?.build(entities, lazy))
+    }
+
+    fn resolve<'s>(&'s self, name: &'s str, visited: &mut Vec<&'s str>) -> Result<Map<String, Value>, String> {
+        if visited.contains(&name) {
+            return Err(format!("Blueprint {} inherits from itself", name));
+        }
+        visited.push(name);
+
+        let def = self.blueprints.get(name)
+            .ok_or_else(|| format!("There is no blueprint named {}", name))?;
+        let mut components = match def.extends {
+            Some(ref parent) => self.resolve(parent, visited)?,
+            None => Map::new()
+        };
+        merge_components(&mut components, &def.components);
+        Ok(components)
+    }
+}
+
+fn merge_components(base: &mut Map<String, Value>, over: &Map<String, Value>) {
+    for (component, value) in over {
+        if value.is_null() {
+            base.remove(component);
+        } else {
+            merge(base.entry(component.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn merge(base: &mut Value, over: &Value) {
+    match (base, over) {
+        (&mut Value::Object(ref mut base), &Value::Object(ref over)) => {
+            for (key, value) in over {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        },
+        (base, over) => *base = over.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::components::Position;
+    use specs::VecStorage;
+
+    #[derive(Deserialize)]
+    struct Speed {
+        walk: f32,
+        run: f32
+    }
+
+    #[derive(Deserialize)]
+    struct Stats {
+        health: i32,
+        speed: Speed
+    }
+
+    crate::define_component!(Stats);
+
+    fn registry(json: &str) -> BlueprintRegistry {
+        let mut registry = BlueprintRegistry::new();
+        registry.register_component::<Position>("Position");
+        registry.register_component::<Stats>("Stats");
+        registry.load_str(json).unwrap();
+        registry
+    }
+
+    fn spawn(prefab: Prefab) -> (World, Entity) {
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<Stats>();
+        let entity = prefab.add_to_world(&mut world);
+        world.maintain();
+        (world, entity)
+    }
+
+    const ACTORS: &str = r#"{
+        "actor": { "components": {
+            "Position": { "x": 1.0, "y": 2.0 },
+            "Stats": { "health": 10, "speed": { "walk": 1.0, "run": 2.0 } }
+        } },
+        "monster": { "extends": "actor", "components": {
+            "Stats": { "health": 20 }
+        } },
+        "boss": { "extends": "monster", "components": {
+            "Position": { "x": 5.0 },
+            "Stats": { "speed": { "run": 4.0 } }
+        } },
+        "statue": { "extends": "actor", "components": { "Stats": null } }
+    }"#;
+
+    #[test]
+    fn children_inherit_along_the_whole_chain() {
+        let registry = registry(ACTORS);
+        let (world, boss) = spawn(registry.prefab("boss").unwrap());
+
+        let positions = world.read_storage::<Position>();
+        let position = positions.get(boss).unwrap();
+        assert_eq!((position.x, position.y), (5.0, 2.0));
+
+        let stats = world.read_storage::<Stats>();
+        let stats = stats.get(boss).unwrap();
+        assert_eq!(stats.health, 20);
+        assert_eq!((stats.speed.walk, stats.speed.run), (1.0, 4.0));
+    }
+
+    #[test]
+    fn null_removes_an_inherited_component() {
+        let registry = registry(ACTORS);
+        let (world, statue) = spawn(registry.prefab("statue").unwrap());
+
+        assert!(world.read_storage::<Position>().get(statue).is_some());
+        assert!(world.read_storage::<Stats>().get(statue).is_none());
+
+        let overrides = serde_json::json!({ "Position": null });
+        let (world, monster) = spawn(registry.prefab_with("monster", &overrides).unwrap());
+        assert!(world.read_storage::<Position>().get(monster).is_none());
+        assert!(world.read_storage::<Stats>().get(monster).is_some());
+    }
+
+    #[test]
+    fn overrides_only_replace_the_fields_they_name() {
+        let registry = registry(ACTORS);
+        let overrides = serde_json::json!({ "Stats": { "speed": { "walk": 0.5 } } });
+        let (world, boss) = spawn(registry.prefab_with("boss", &overrides).unwrap());
+
+        let stats = world.read_storage::<Stats>();
+        let stats = stats.get(boss).unwrap();
+        assert_eq!(stats.health, 20);
+        assert_eq!((stats.speed.walk, stats.speed.run), (0.5, 4.0));
+    }
+
+    #[test]
+    fn components_arrive_when_the_world_is_maintained() {
+        let registry = registry(ACTORS);
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<Stats>();
+
+        let actor = registry.prefab("actor").unwrap().add_to_world(&mut world);
+        assert!(world.read_storage::<Position>().get(actor).is_none());
+        world.maintain();
+        assert!(world.read_storage::<Position>().get(actor).is_some());
+    }
+
+    #[test]
+    fn unknown_parents_are_errors() {
+        let registry = registry(r#"{ "ghost": { "extends": "spirit" } }"#);
+        let error = registry.prefab("ghost").err().unwrap();
+        assert_eq!(error, "There is no blueprint named spirit");
+    }
+
+    #[test]
+    fn extends_cycles_are_errors() {
+        let registry = registry(r#"{
+            "egg": { "extends": "chicken" },
+            "chicken": { "extends": "egg" },
+            "ouroboros": { "extends": "ouroboros" }
+        }"#);
+        assert_eq!(registry.prefab("egg").err().unwrap(), "Blueprint egg inherits from itself");
+        assert_eq!(registry.prefab("ouroboros").err().unwrap(), "Blueprint ouroboros inherits from itself");
+    }
+}
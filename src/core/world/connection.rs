@@ -1,4 +1,7 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use super::ZLevel;
+use crate::tiles::{ChunkView, TileChange, TileDef, TileID};
+use crate::lighting::LightView;
 
 #[derive(Clone, Debug, Default)]
 pub struct ConnectionCollection {
@@ -14,10 +17,63 @@ pub struct Connection {
     pub key: String
 }
 
+/// Everything a client needs to draw one entity. The optional fields are left out of the
+/// serialized view when the entity doesn't have the matching component.
+#[derive(Clone, Debug, Serialize)]
+pub struct EntityView {
+    /// The entity's `NetworkId`, which stays the same for as long as the entity lives.
+    pub id: u64,
+    pub sprite: u64,
+    pub x: f32,
+    pub y: f32,
+    /// Index into the view's `layers`.
+    pub layer: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub z_order: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rotation: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<(f32, f32)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flip: Option<(bool, bool)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tint: Option<(u8, u8, u8)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opacity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<u32>
+}
+
+// Entities are sorted back to front. `layers` only lists the z-levels that appear in the view.
+// `spawned` and `despawned` hold the ids that entered or left this client's view since the
+// last view it was sent. `tick`, `time` and `last_input` (the sequence number of the last
+// input message from this client the server has applied) are filled in by the engine when the
+// view is sent.
 #[derive(Clone, Debug, Serialize)]
 pub struct ClientView {
-    pub sprites: Vec<u64>,
-    pub loc: Vec<(f32, f32)>
+    pub tick: u64,
+    pub time: f64,
+    pub last_input: Option<u64>,
+    pub entities: Vec<EntityView>,
+    pub layers: Vec<ZLevel>,
+    pub spawned: Vec<u64>,
+    pub despawned: Vec<u64>,
+    // Tile map data, see `TileSyncSystem`. Clients apply `chunks` before `tile_changes`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tile_defs: Option<BTreeMap<TileID, TileDef>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<ChunkView>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tile_changes: Vec<TileChange>,
+    // Only set when the light around this client changed, see `LightSyncSystem`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub light: Option<LightView>
+}
+
+/// Hands out the ids in `NetworkId`s. Ids are never reused while the server is running.
+#[derive(Clone, Debug)]
+pub struct NetworkIdAllocator {
+    next: u64
 }
 
 impl ConnectionCollection {
@@ -51,7 +107,7 @@ impl ConnectionCollection {
     }
 
     pub fn remove(&mut self, key: &String) {
-        self.connections.retain(|x| x.key == *key);
+        self.connections.retain(|x| x.key != *key);
     }
 
     pub fn push(&mut self, c: Connection) {
@@ -60,11 +116,150 @@ impl ConnectionCollection {
     }
 }
 
+impl EntityView {
+    pub fn new(id: u64, sprite: u64, loc: (f32, f32)) -> Self {
+        EntityView {
+            id,
+            sprite,
+            x: loc.0,
+            y: loc.1,
+            layer: 0,
+            z_order: None,
+            rotation: None,
+            scale: None,
+            flip: None,
+            tint: None,
+            opacity: None,
+            frame: None
+        }
+    }
+}
+
 impl ClientView {
     pub fn new() -> Self {
         ClientView {
-            sprites: vec!(),
-            loc: vec!()
+            tick: 0,
+            time: 0.0,
+            last_input: None,
+            entities: vec!(),
+            layers: vec!(),
+            spawned: vec!(),
+            despawned: vec!(),
+            tile_defs: None,
+            chunks: vec!(),
+            tile_changes: vec!(),
+            light: None
+        }
+    }
+
+    /// Folds a view that was never sent into this newer one, so no spawns or despawns are
+    /// lost when the stream falls behind and skips views.
+    pub fn absorb(&mut self, older: ClientView) {
+        // An entity can leave a camera's view and come back under the same id, so each id
+        // goes by whatever happened to it last. That can despawn an id the client never saw.
+        let newer = |id: &&u64| !self.spawned.contains(id) && !self.despawned.contains(id);
+        let mut spawned: Vec<u64> = older.spawned.iter().filter(&newer).cloned().collect();
+        let mut despawned: Vec<u64> = older.despawned.iter().filter(&newer).cloned().collect();
+        spawned.extend(self.spawned.drain(..));
+        despawned.extend(self.despawned.drain(..));
+        self.spawned = spawned;
+        self.despawned = despawned;
+
+        // Older tile changes are already part of any chunk this view sends in full
+        let resent = &self.chunks;
+        let mut tile_changes: Vec<TileChange> = older.tile_changes.into_iter()
+            .filter(|change| !resent.iter().any(|chunk| {
+                chunk.layer == change.layer && chunk.contains(change.x, change.y)
+            }))
+            .collect();
+        tile_changes.extend(self.tile_changes.drain(..));
+        self.tile_changes = tile_changes;
+
+        let mut chunks = older.chunks;
+        chunks.extend(self.chunks.drain(..));
+        self.chunks = chunks;
+
+        if self.tile_defs.is_none() {
+            self.tile_defs = older.tile_defs;
+        }
+        if self.light.is_none() {
+            self.light = older.light;
         }
     }
-}
\ No newline at end of file
+
+    /// Adds an entity to the view on the given z-level. The view has to be sorted with
+    /// `sort_layers` once it's complete.
+    pub fn push(&mut self, mut entity: EntityView, z_level: &ZLevel) {
+        let index = match self.layers.iter().position(|l| l.name == z_level.name) {
+            Some(index) => index,
+            None => {
+                self.layers.push(z_level.clone());
+                self.layers.len() - 1
+            }
+        };
+        entity.layer = index as u16;
+        self.entities.push(entity);
+    }
+
+    /// Sorts entities by z-level, then by z-order. Entities that tie keep the order they were
+    /// pushed in.
+    pub fn sort_layers(&mut self) {
+        let layers = &self.layers;
+        self.entities.sort_by_key(|e| (layers[e.layer as usize].order, e.z_order.unwrap_or(0)));
+    }
+}
+
+impl Default for NetworkIdAllocator {
+    fn default() -> Self {
+        NetworkIdAllocator::new()
+    }
+}
+
+impl NetworkIdAllocator {
+    pub fn new() -> Self {
+        NetworkIdAllocator {
+            next: 1
+        }
+    }
+
+    pub fn allocate(&mut self) -> u64 {
+        let id = self.next;
+        self.next += 1;
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(spawned: Vec<u64>, despawned: Vec<u64>) -> ClientView {
+        ClientView {
+            spawned,
+            despawned,
+            ..ClientView::new()
+        }
+    }
+
+    #[test]
+    fn absorb_keeps_spawns_and_despawns() {
+        let mut newer = view(vec!(3), vec!(4));
+        newer.absorb(view(vec!(1), vec!(2)));
+        assert_eq!((newer.spawned, newer.despawned), (vec!(1, 3), vec!(2, 4)));
+    }
+
+    #[test]
+    fn absorb_goes_by_latest_event() {
+        // 1 left the view and came back, 2 came into view and left
+        let mut newer = view(vec!(1), vec!(2));
+        newer.absorb(view(vec!(2), vec!(1)));
+        assert_eq!(newer.spawned, vec!(1));
+        assert_eq!(newer.despawned, vec!(2));
+
+        // 1 left again
+        let mut newest = view(vec!(), vec!(1));
+        newest.absorb(newer);
+        assert!(newest.spawned.is_empty());
+        assert_eq!(newest.despawned, vec!(2, 1));
+    }
+}
@@ -1,5 +1,378 @@
-#[derive(Clone, PartialEq, Eq, Debug)]
+use crate::utils::*;
+use specs::prelude::*;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Deserialize)]
+pub struct Modifiers {
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub alt: bool,
+    #[serde(default)]
+    pub meta: bool
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Deserialize)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+    Other(u8)
+}
+
+// Clients send inputs other than `keys` and `clicks` as a list of `events` in their input
+// messages, tagged with their "type":
+//
+// { "seq": 4, "events": [{ "type": "KeyDown", "key": "w", "modifiers": { "shift": true } },
+//                        { "type": "MouseMove", "x": 310.0, "y": 92.5 }] }
+//
+// Gamepads are numbered from 0, and their buttons and axes are named by the client.
+#[derive(Clone, PartialEq, Debug, Deserialize)]
+#[serde(tag = "type")]
 pub enum Input {
+    /// A left click, from the message's `clicks`.
     Click { x: u32, y: u32 },
-    Key(String)
-}
\ No newline at end of file
+    /// A key press with no matching release, from the message's `keys`.
+    #[serde(skip)]
+    Key(String),
+    KeyDown {
+        key: String,
+        #[serde(default)]
+        modifiers: Modifiers
+    },
+    KeyUp {
+        key: String,
+        #[serde(default)]
+        modifiers: Modifiers
+    },
+    MouseDown {
+        button: MouseButton,
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        modifiers: Modifiers
+    },
+    MouseUp {
+        button: MouseButton,
+        x: f32,
+        y: f32,
+        #[serde(default)]
+        modifiers: Modifiers
+    },
+    MouseMove { x: f32, y: f32 },
+    Scroll { dx: f32, dy: f32 },
+    /// Typed text, after the keyboard layout and any input method have been applied.
+    Text { text: String },
+    GamepadButton { gamepad: u8, button: String, pressed: bool },
+    /// From -1.0 to 1.0, or 0.0 to 1.0 for triggers.
+    GamepadAxis { gamepad: u8, axis: String, value: f32 }
+}
+
+/// An input along with the sequence number of the client message it arrived in.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SequencedInput {
+    pub seq: u64,
+    pub input: Input
+}
+
+// The last input sequence number the engine has handed to systems for each connection.
+// Clients use it to drop inputs the server has applied from their prediction buffer.
+#[derive(Clone, Debug, Default)]
+pub struct InputAcks {
+    last: HashMap<String, u64>
+}
+
+impl InputAcks {
+    pub fn new() -> Self {
+        InputAcks {
+            last: HashMap::new()
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<u64> {
+        self.last.get(key).cloned()
+    }
+
+    pub(crate) fn acknowledge(&mut self, key: String, seq: u64) {
+        let last = self.last.entry(key).or_insert(seq);
+        if seq > *last {
+            *last = seq;
+        }
+    }
+}
+
+// What one player is pressing and pointing at, kept up to date by `InputStateSystem`. Keys,
+// buttons and axes stay down until they're released, while the presses, releases, scrolling
+// and text are only for the current tick. A `Key` or `Click` counts as pressed and released
+// on the same tick.
+#[derive(Clone, Debug, Default)]
+pub struct InputState {
+    keys_down: HashSet<String>,
+    keys_pressed: HashSet<String>,
+    keys_released: HashSet<String>,
+    modifiers: Modifiers,
+    buttons_down: HashSet<MouseButton>,
+    buttons_pressed: HashSet<MouseButton>,
+    buttons_released: HashSet<MouseButton>,
+    mouse: (f32, f32),
+    scroll: (f32, f32),
+    text: String,
+    gamepad_buttons_down: HashSet<(u8, String)>,
+    gamepad_buttons_pressed: HashSet<(u8, String)>,
+    gamepad_axes: HashMap<(u8, String), f32>
+}
+
+impl InputState {
+    pub fn is_key_down(&self, key: &str) -> bool {
+        self.keys_down.contains(key)
+    }
+
+    pub fn was_key_pressed(&self, key: &str) -> bool {
+        self.keys_pressed.contains(key)
+    }
+
+    pub fn was_key_released(&self, key: &str) -> bool {
+        self.keys_released.contains(key)
+    }
+
+    /// The modifiers held with the last key or mouse button pressed or released.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn is_button_down(&self, button: MouseButton) -> bool {
+        self.buttons_down.contains(&button)
+    }
+
+    pub fn was_button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons_pressed.contains(&button)
+    }
+
+    pub fn was_button_released(&self, button: MouseButton) -> bool {
+        self.buttons_released.contains(&button)
+    }
+
+    /// Where the mouse was last seen.
+    pub fn mouse(&self) -> (f32, f32) {
+        self.mouse
+    }
+
+    /// How far the player scrolled this tick.
+    pub fn scroll(&self) -> (f32, f32) {
+        self.scroll
+    }
+
+    /// Text typed this tick.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_gamepad_button_down(&self, gamepad: u8, button: &str) -> bool {
+        self.gamepad_buttons_down.contains(&(gamepad, button.to_string()))
+    }
+
+    pub fn was_gamepad_button_pressed(&self, gamepad: u8, button: &str) -> bool {
+        self.gamepad_buttons_pressed.contains(&(gamepad, button.to_string()))
+    }
+
+    /// Whether the button is down on any gamepad, or was pressed on one this tick.
+    pub fn any_gamepad_button(&self, button: &str) -> bool {
+        self.gamepad_buttons_down.iter().chain(self.gamepad_buttons_pressed.iter())
+            .any(|(_, b)| b == button)
+    }
+
+    /// Whether the button was pressed on any gamepad this tick.
+    pub fn any_gamepad_button_pressed(&self, button: &str) -> bool {
+        self.gamepad_buttons_pressed.iter().any(|(_, b)| b == button)
+    }
+
+    /// 0.0 until the axis has moved.
+    pub fn axis(&self, gamepad: u8, axis: &str) -> f32 {
+        self.gamepad_axes.get(&(gamepad, axis.to_string())).cloned().unwrap_or(0.0)
+    }
+
+    fn start_tick(&mut self) {
+        self.keys_pressed.clear();
+        self.keys_released.clear();
+        self.buttons_pressed.clear();
+        self.buttons_released.clear();
+        self.gamepad_buttons_pressed.clear();
+        self.scroll = (0.0, 0.0);
+        self.text.clear();
+    }
+
+    fn apply(&mut self, input: &Input) {
+        match *input {
+            Input::Click { x, y } => {
+                self.mouse = (x as f32, y as f32);
+                self.buttons_pressed.insert(MouseButton::Left);
+                self.buttons_released.insert(MouseButton::Left);
+            },
+            Input::Key(ref key) => {
+                self.keys_pressed.insert(key.clone());
+                self.keys_released.insert(key.clone());
+            },
+            Input::KeyDown { ref key, modifiers } => {
+                // Held keys repeat, which isn't a new press
+                if self.keys_down.insert(key.clone()) {
+                    self.keys_pressed.insert(key.clone());
+                }
+                self.modifiers = modifiers;
+            },
+            Input::KeyUp { ref key, modifiers } => {
+                if self.keys_down.remove(key) {
+                    self.keys_released.insert(key.clone());
+                }
+                self.modifiers = modifiers;
+            },
+            Input::MouseDown { button, x, y, modifiers } => {
+                if self.buttons_down.insert(button) {
+                    self.buttons_pressed.insert(button);
+                }
+                self.mouse = (x, y);
+                self.modifiers = modifiers;
+            },
+            Input::MouseUp { button, x, y, modifiers } => {
+                if self.buttons_down.remove(&button) {
+                    self.buttons_released.insert(button);
+                }
+                self.mouse = (x, y);
+                self.modifiers = modifiers;
+            },
+            Input::MouseMove { x, y } => {
+                self.mouse = (x, y);
+            },
+            Input::Scroll { dx, dy } => {
+                self.scroll = (self.scroll.0 + dx, self.scroll.1 + dy);
+            },
+            Input::Text { ref text } => {
+                self.text.push_str(text);
+            },
+            Input::GamepadButton { gamepad, ref button, pressed } => {
+                let id = (gamepad, button.clone());
+                if !pressed {
+                    self.gamepad_buttons_down.remove(&id);
+                } else if self.gamepad_buttons_down.insert(id.clone()) {
+                    self.gamepad_buttons_pressed.insert(id);
+                }
+            },
+            Input::GamepadAxis { gamepad, ref axis, value } => {
+                self.gamepad_axes.insert((gamepad, axis.clone()), value);
+            }
+        }
+    }
+}
+
+/// The `InputState` of every connection.
+#[derive(Clone, Debug, Default)]
+pub struct InputStates {
+    players: HashMap<String, InputState>
+}
+
+impl InputStates {
+    pub fn new() -> Self {
+        InputStates::default()
+    }
+
+    pub fn get(&self, player: &str) -> Option<&InputState> {
+        self.players.get(player)
+    }
+}
+
+// Applies each connection's inputs for the tick to its `InputState`, in the order they were
+// sent. The engine runs it at the start of every tick; the inputs are left in the `InputMap`
+// for other systems.
+pub struct InputStateSystem;
+
+impl<'a> System<'a> for InputStateSystem {
+    type SystemData = (ReadInputMap<'a>,
+    ReadConnections<'a>,
+    Write<'a, InputStates>);
+
+    fn run(&mut self, (inputs, connections, mut states): Self::SystemData) {
+        let mut previous = ::std::mem::replace(&mut states.players, HashMap::new());
+
+        for conn in &connections.connections {
+            let mut state = previous.remove(&conn.key).unwrap_or_default();
+            state.start_tick();
+            for input in inputs.get(&conn.key).into_iter().flatten() {
+                state.apply(&input.input);
+            }
+            states.players.insert(conn.key.clone(), state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{Connection, ConnectionCollection};
+
+    #[test]
+    fn mouse_buttons_carry_modifiers() {
+        let events: Vec<Input> = serde_json::from_str(r#"[
+            { "type": "MouseDown", "button": "Right", "x": 10.0, "y": 20.0, "modifiers": { "ctrl": true } },
+            { "type": "MouseMove", "x": 12.0, "y": 24.0 }
+        ]"#).unwrap();
+
+        let mut state = InputState::default();
+        for event in &events {
+            state.apply(event);
+        }
+        assert!(state.is_button_down(MouseButton::Right));
+        assert!(state.was_button_pressed(MouseButton::Right));
+        assert_eq!(state.modifiers(), Modifiers { ctrl: true, ..Modifiers::default() });
+        assert_eq!(state.mouse(), (12.0, 24.0));
+
+        state.start_tick();
+        state.apply(&Input::MouseUp { button: MouseButton::Right, x: 12.0, y: 24.0, modifiers: Modifiers::default() });
+        assert!(!state.is_button_down(MouseButton::Right));
+        assert!(state.was_button_released(MouseButton::Right));
+        assert_eq!(state.modifiers(), Modifiers::default());
+    }
+
+    fn run_tick(world: &mut World, inputs: Vec<Input>) {
+        let mut map = InputMap::new();
+        map.insert("p1".to_string(), inputs.into_iter().map(|input| SequencedInput { seq: 0, input }).collect());
+        world.add_resource(map);
+        InputStateSystem.run_now(&world.res);
+    }
+
+    fn one_player() -> World {
+        let mut world = World::new();
+        let mut connections = ConnectionCollection::new();
+        connections.push(Connection { key: "p1".to_string() });
+        world.add_resource(connections);
+        world.add_resource(InputStates::new());
+        world
+    }
+
+    fn key_down(key: &str) -> Input {
+        Input::KeyDown { key: key.to_string(), modifiers: Modifiers::default() }
+    }
+
+    #[test]
+    fn held_keys_stay_down_across_ticks() {
+        let mut world = one_player();
+        run_tick(&mut world, vec!(key_down("w")));
+        for _ in 0..3 {
+            run_tick(&mut world, vec!());
+            let states = world.read_resource::<InputStates>();
+            let state = states.get("p1").unwrap();
+            assert!(state.is_key_down("w"));
+            assert!(!state.was_key_pressed("w"));
+        }
+        run_tick(&mut world, vec!(Input::KeyUp { key: "w".to_string(), modifiers: Modifiers::default() }));
+        let states = world.read_resource::<InputStates>();
+        assert!(!states.get("p1").unwrap().is_key_down("w"));
+        assert!(states.get("p1").unwrap().was_key_released("w"));
+    }
+
+    #[test]
+    fn modifiers_are_optional() {
+        let event: Input = serde_json::from_str(r#"{ "type": "MouseUp", "button": "Left", "x": 0.0, "y": 0.0 }"#).unwrap();
+        assert_eq!(event, Input::MouseUp { button: MouseButton::Left, x: 0.0, y: 0.0, modifiers: Modifiers::default() });
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use crate::components::DEFAULT_Z_LEVEL;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ZLevel {
+    pub name: String,
+    /// Lower orders are drawn first.
+    pub order: i32,
+    /// How far the layer scrolls relative to the camera, (1.0, 1.0) being in step with it.
+    pub parallax: (f32, f32)
+}
+
+// The table of z-levels `Position::z_level` can refer to. Entities on a z-level that was
+// never registered are drawn as if they were on the default one.
+#[derive(Clone, Debug)]
+pub struct ZLevels {
+    levels: HashMap<String, ZLevel>
+}
+
+impl Default for ZLevels {
+    fn default() -> Self {
+        ZLevels::new()
+    }
+}
+
+impl ZLevels {
+    pub fn new() -> Self {
+        let mut levels = ZLevels {
+            levels: HashMap::new()
+        };
+        levels.register(DEFAULT_Z_LEVEL, 0, (1.0, 1.0));
+        levels
+    }
+
+    pub fn register(&mut self, name: &str, order: i32, parallax: (f32, f32)) {
+        self.levels.insert(name.to_string(), ZLevel {
+            name: name.to_string(),
+            order,
+            parallax
+        });
+    }
+
+    pub fn get(&self, name: &str) -> &ZLevel {
+        self.levels.get(name)
+            .unwrap_or_else(|| &self.levels[DEFAULT_Z_LEVEL])
+    }
+}
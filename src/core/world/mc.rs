@@ -1,4 +1,5 @@
 use super::World;
+use super::Transition;
 
 pub trait MasterController {
     type ObserverEvent;
@@ -6,6 +7,17 @@ pub trait MasterController {
     fn tick(&mut self, _world: &mut World, _delta_time: f64) -> EngineInstruction { EngineInstruction::Run {
         run_dispatcher: true
     } }
+    /// Called after the dispatcher has run and before views are sent, with every event
+    /// the systems pushed into `Messages` this tick. Returning an instruction overrides
+    /// the one returned by `tick` on the next tick.
+    fn on_events(&mut self, _world: &mut World, _events: &[Self::ObserverEvent]) -> Option<EngineInstruction> { None }
+    /// Called when this controller becomes the active state, including when the state
+    /// pushed over it is popped.
+    fn on_enter(&mut self, _world: &mut World) {}
+    /// Called when this controller is popped, switched away from or covered by a pushed state.
+    fn on_exit(&mut self, _world: &mut World) {}
+    /// Polled at the end of every tick to move the engine to another state.
+    fn transition(&mut self, _world: &mut World) -> Transition<Self::ObserverEvent> { Transition::None }
 }
 
 pub enum EngineInstruction {
@@ -14,4 +26,4 @@ pub enum EngineInstruction {
     },
     Pause,
     Restart
-}
\ No newline at end of file
+}
@@ -3,14 +3,47 @@ mod input;
 mod mc;
 mod system;
 mod blueprint;
+mod state;
+mod layers;
+mod time;
+mod scheduler;
+mod action;
 
-pub use connection::{ConnectionCollection, Connection, ClientView};
-pub use input::Input;
+pub use connection::{ConnectionCollection, Connection, ClientView, EntityView, NetworkIdAllocator};
+pub use input::{Input, SequencedInput, InputAcks, Modifiers, MouseButton, InputState, InputStates, InputStateSystem};
 pub use mc::{MasterController, EngineInstruction};
-pub use system::{SystemExecutor, SystemExecutorBuilder};
+pub use system::{SystemExecutor, SystemExecutorBuilder, Stage};
+pub use state::{StateStack, Transition};
+pub use blueprint::{Blueprint, BlueprintRegistry, Prefab};
+pub use layers::{ZLevel, ZLevels};
+pub use time::{ServerTime, DEFAULT_TICK_RATE};
+pub use scheduler::{Scheduler, SchedulerSystem, Delay, TimerId, TimerAction, TimerCallback};
+pub use action::{ActionMap, ActionStates, ActionState, ActionSystem, Binding};
+
+use specs::Entity;
 
 pub struct World<'a, 'b> {
     pub(crate) system_executor: SystemExecutor<'a, 'b>,
     pub ecs_world: specs::prelude::World,
     pub connections: ConnectionCollection,
-}
\ No newline at end of file
+}
+
+impl<'a, 'b> World<'a, 'b> {
+    pub fn enable_system_group(&mut self, group: &str) {
+        self.system_executor.set_group_enabled(group, true);
+    }
+
+    pub fn disable_system_group(&mut self, group: &str) {
+        self.system_executor.set_group_enabled(group, false);
+    }
+
+    pub fn is_system_group_enabled(&self, group: &str) -> bool {
+        self.system_executor.is_group_enabled(group)
+    }
+
+    /// The entity's components are added when the world is next maintained.
+    pub fn spawn_blueprint(&mut self, name: &str) -> Result<Entity, String> {
+        let prefab = self.ecs_world.read_resource::<BlueprintRegistry>().prefab(name)?;
+        Ok(prefab.add_to_world(&mut self.ecs_world))
+    }
+}
@@ -0,0 +1,288 @@
+use super::ServerTime;
+use crate::utils::Messages;
+use specs::prelude::*;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delay {
+    /// Counted in ticks the dispatcher runs, so a delay of one tick fires on the next one.
+    Ticks(u64),
+    Seconds(f64)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(u64);
+
+pub type TimerCallback = Arc<Fn(&mut World) + Send + Sync>;
+
+#[derive(Clone)]
+pub enum TimerAction<E> {
+    /// Pushed into `Messages<E>`, so the master controller gets it in `on_events`.
+    Event(E),
+    /// Run on the world when it's next maintained.
+    Call(TimerCallback)
+}
+
+struct Timer<E> {
+    interval: Delay,
+    remaining: Delay,
+    repeat: bool,
+    action: TimerAction<E>,
+    entity: Option<Entity>
+}
+
+// Timers that fire events or callbacks after a delay, once or over and over, kept up to date
+// by `SchedulerSystem`. Because that system runs with the rest of the dispatcher, timers
+// don't count down while the engine is paused. A timer attached to an entity is cancelled
+// when the entity is deleted.
+//
+//     scheduler.after(Delay::Seconds(3.0), GameEvent::Respawn(player));
+//     let id = scheduler.every(Delay::Ticks(10), GameEvent::SpawnWave);
+//     scheduler.attach(id, spawner);
+pub struct Scheduler<E> {
+    timers: BTreeMap<TimerId, Timer<E>>,
+    next_id: u64
+}
+
+impl<E> Default for Scheduler<E> {
+    fn default() -> Self {
+        Scheduler::new()
+    }
+}
+
+impl<E> Scheduler<E> {
+    pub fn new() -> Self {
+        Scheduler {
+            timers: BTreeMap::new(),
+            next_id: 0
+        }
+    }
+
+    pub fn schedule(&mut self, delay: Delay, repeat: bool, action: TimerAction<E>) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.timers.insert(id, Timer {
+            interval: delay,
+            remaining: delay,
+            repeat,
+            action,
+            entity: None
+        });
+        id
+    }
+
+    pub fn after(&mut self, delay: Delay, event: E) -> TimerId {
+        self.schedule(delay, false, TimerAction::Event(event))
+    }
+
+    pub fn every(&mut self, delay: Delay, event: E) -> TimerId {
+        self.schedule(delay, true, TimerAction::Event(event))
+    }
+
+    pub fn after_call<F>(&mut self, delay: Delay, callback: F) -> TimerId
+    where
+        F: Fn(&mut World) + Send + Sync + 'static {
+        self.schedule(delay, false, TimerAction::Call(Arc::new(callback)))
+    }
+
+    pub fn every_call<F>(&mut self, delay: Delay, callback: F) -> TimerId
+    where
+        F: Fn(&mut World) + Send + Sync + 'static {
+        self.schedule(delay, true, TimerAction::Call(Arc::new(callback)))
+    }
+
+    /// Cancels the timer when `entity` is deleted. Returns false if the timer isn't pending.
+    pub fn attach(&mut self, id: TimerId, entity: Entity) -> bool {
+        match self.timers.get_mut(&id) {
+            Some(timer) => {
+                timer.entity = Some(entity);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Returns false if the timer already fired or was cancelled.
+    pub fn cancel(&mut self, id: TimerId) -> bool {
+        self.timers.remove(&id).is_some()
+    }
+
+    pub fn is_pending(&self, id: TimerId) -> bool {
+        self.timers.contains_key(&id)
+    }
+
+    /// How long until the timer next fires.
+    pub fn remaining(&self, id: TimerId) -> Option<Delay> {
+        self.timers.get(&id).map(|timer| timer.remaining)
+    }
+
+    pub fn len(&self) -> usize {
+        self.timers.len()
+    }
+}
+
+// Counts every timer in the `Scheduler` down and fires the ones that are due, at most once
+// each per tick. The engine runs it in `Stage::Engine`, before any of the game's systems.
+pub struct SchedulerSystem<E> {
+    phantom: ::std::marker::PhantomData<E>
+}
+
+impl<E> SchedulerSystem<E> {
+    pub fn new() -> Self {
+        SchedulerSystem {
+            phantom: ::std::marker::PhantomData
+        }
+    }
+}
+
+impl<'a, E: Clone + Send + Sync + 'static> System<'a> for SchedulerSystem<E> {
+    type SystemData = (Entities<'a>,
+    Read<'a, ServerTime>,
+    Read<'a, LazyUpdate>,
+    Write<'a, Scheduler<E>>,
+    Write<'a, Messages<E>>);
+
+    fn run(&mut self, (entities, time, lazy, mut scheduler, mut messages): Self::SystemData) {
+        let mut done = vec!();
+
+        for (id, timer) in scheduler.timers.iter_mut() {
+            if let Some(entity) = timer.entity {
+                if !entities.is_alive(entity) {
+                    done.push(*id);
+                    continue;
+                }
+            }
+
+            let due = match timer.remaining {
+                Delay::Ticks(ref mut ticks) => {
+                    *ticks = ticks.saturating_sub(1);
+                    *ticks == 0
+                },
+                Delay::Seconds(ref mut seconds) => {
+                    *seconds -= time.delta;
+                    *seconds <= 0.0
+                }
+            };
+            if !due {
+                continue;
+            }
+
+            match timer.action {
+                TimerAction::Event(ref event) => messages.push(event.clone()),
+                TimerAction::Call(ref callback) => {
+                    let callback = callback.clone();
+                    lazy.exec_mut(move |world| callback(world));
+                }
+            }
+
+            if timer.repeat {
+                timer.remaining = match (timer.interval, timer.remaining) {
+                    // Time overshot this tick comes off the next wait
+                    (Delay::Seconds(interval), Delay::Seconds(over)) => Delay::Seconds((interval + over).max(0.0)),
+                    (interval, _) => interval
+                };
+            } else {
+                done.push(*id);
+            }
+        }
+
+        for id in done {
+            scheduler.timers.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Clock {
+        world: World,
+        system: SchedulerSystem<&'static str>
+    }
+
+    impl Clock {
+        fn new() -> Self {
+            let mut world = World::new();
+            let mut system = SchedulerSystem::new();
+            System::setup(&mut system, &mut world.res);
+            Clock { world, system }
+        }
+
+        fn scheduler(&self) -> ::specs::shred::FetchMut<Scheduler<&'static str>> {
+            self.world.write_resource::<Scheduler<&'static str>>()
+        }
+
+        // Runs one tick of `delta` seconds and returns the events fired
+        fn tick(&mut self, delta: f64) -> Vec<&'static str> {
+            self.world.write_resource::<ServerTime>().delta = delta;
+            self.system.run_now(&self.world.res);
+            self.world.maintain();
+            ::std::mem::replace(&mut *self.world.write_resource::<Messages<&'static str>>(), vec!())
+        }
+    }
+
+    #[test]
+    fn ticks_count_dispatches_and_seconds_count_time() {
+        let mut clock = Clock::new();
+        clock.scheduler().after(Delay::Ticks(2), "ticks");
+        clock.scheduler().after(Delay::Seconds(1.0), "seconds");
+        assert_eq!(clock.tick(0.5), Vec::<&str>::new());
+        assert_eq!(clock.tick(0.125), vec!("ticks"));
+        assert_eq!(clock.tick(0.25), Vec::<&str>::new());
+        assert_eq!(clock.tick(0.125), vec!("seconds"));
+        assert_eq!(clock.scheduler().len(), 0);
+    }
+
+    #[test]
+    fn repeating_timers_keep_firing() {
+        let mut clock = Clock::new();
+        let id = clock.scheduler().every(Delay::Ticks(2), "wave");
+        let fired: Vec<usize> = (0..6).map(|_| clock.tick(0.1).len()).collect();
+        assert_eq!(fired, vec!(0, 1, 0, 1, 0, 1));
+        assert!(clock.scheduler().cancel(id));
+        assert_eq!(clock.tick(0.1), Vec::<&str>::new());
+        assert!(!clock.scheduler().cancel(id));
+    }
+
+    #[test]
+    fn overshoot_comes_off_the_next_wait() {
+        let mut clock = Clock::new();
+        let id = clock.scheduler().every(Delay::Seconds(1.0), "tick");
+        assert_eq!(clock.tick(1.25), vec!("tick"));
+        assert_eq!(clock.scheduler().remaining(id), Some(Delay::Seconds(0.75)));
+        // A tick longer than the period only fires once, and what's left over past the
+        // next wait is dropped
+        assert_eq!(clock.tick(2.5), vec!("tick"));
+        assert_eq!(clock.scheduler().remaining(id), Some(Delay::Seconds(0.0)));
+        assert_eq!(clock.tick(0.01), vec!("tick"));
+        assert_eq!(clock.scheduler().remaining(id), Some(Delay::Seconds(0.99)));
+    }
+
+    #[test]
+    fn callbacks_run_on_the_world() {
+        let mut clock = Clock::new();
+        clock.world.add_resource(0u32);
+        clock.scheduler().after_call(Delay::Ticks(1), |world: &mut World| *world.write_resource::<u32>() += 1);
+        clock.scheduler().every_call(Delay::Ticks(1), |world: &mut World| *world.write_resource::<u32>() += 10);
+        assert_eq!(clock.tick(0.1), Vec::<&str>::new());
+        assert_eq!(*clock.world.read_resource::<u32>(), 11);
+        clock.tick(0.1);
+        assert_eq!(*clock.world.read_resource::<u32>(), 21);
+    }
+
+    #[test]
+    fn timers_are_cancelled_with_their_entity() {
+        let mut clock = Clock::new();
+        let entity = clock.world.create_entity().build();
+        let attached = clock.scheduler().after(Delay::Ticks(2), "attached");
+        let free = clock.scheduler().after(Delay::Ticks(2), "free");
+        assert!(clock.scheduler().attach(attached, entity));
+        clock.tick(0.1);
+        clock.world.delete_entity(entity).unwrap();
+        assert_eq!(clock.tick(0.1), vec!("free"));
+        assert!(!clock.scheduler().is_pending(attached));
+        assert!(!clock.scheduler().attach(free, entity));
+    }
+}
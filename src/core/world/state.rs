@@ -0,0 +1,149 @@
+use super::{World, MasterController};
+
+/// A change to the engine's state stack, requested by the active state.
+pub enum Transition<E> {
+    None,
+    /// Enter a new state on top of the active one. The active state stays on the stack.
+    Push(Box<MasterController<ObserverEvent=E>>),
+    /// Exit the active state and return to the one below it.
+    Pop,
+    /// Exit the active state and replace it with a new one.
+    Switch(Box<MasterController<ObserverEvent=E>>)
+}
+
+// Game phases (lobby, match, scoreboard...) are each their own master controller. Only the
+// top of the stack is ticked; states below it are kept around until it is popped.
+pub struct StateStack<E> {
+    states: Vec<Box<MasterController<ObserverEvent=E>>>
+}
+
+impl<E> StateStack<E> {
+    pub fn new(initial: Box<MasterController<ObserverEvent=E>>) -> Self {
+        StateStack {
+            states: vec!(initial)
+        }
+    }
+
+    pub fn active(&mut self) -> &mut Box<MasterController<ObserverEvent=E>> {
+        self.states.last_mut().expect("Engine fault: The state stack is empty")
+    }
+
+    pub fn depth(&self) -> usize {
+        self.states.len()
+    }
+
+    /// Moves to another state. States get `on_exit` when they're covered by a pushed state as
+    /// well as when they're popped or switched away from, and `on_enter` when they're
+    /// uncovered again. New states get `start` and then `on_enter`.
+    pub fn apply(&mut self, transition: Transition<E>, world: &mut World) -> Result<(), String> {
+        match transition {
+            Transition::None => {},
+            Transition::Push(mut state) => {
+                self.active().on_exit(world);
+                state.start(world, 0.0);
+                state.on_enter(world);
+                self.states.push(state);
+            },
+            Transition::Pop => {
+                if self.states.len() == 1 {
+                    return Err("The last state cannot be popped off the state stack".to_string());
+                }
+                let mut state = self.states.pop().unwrap();
+                state.on_exit(world);
+                self.active().on_enter(world);
+            },
+            Transition::Switch(mut state) => {
+                let mut old = self.states.pop().unwrap();
+                old.on_exit(world);
+                state.start(world, 0.0);
+                state.on_enter(world);
+                self.states.push(state);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::{ConnectionCollection, SystemExecutor};
+    use std::sync::{Arc, Mutex};
+
+    struct Recorder {
+        name: &'static str,
+        log: Arc<Mutex<Vec<String>>>
+    }
+
+    impl Recorder {
+        fn new(name: &'static str, log: &Arc<Mutex<Vec<String>>>) -> Box<Recorder> {
+            Box::new(Recorder { name, log: log.clone() })
+        }
+
+        fn record(&self, hook: &str) {
+            self.log.lock().unwrap().push(format!("{} {}", self.name, hook));
+        }
+    }
+
+    impl MasterController for Recorder {
+        type ObserverEvent = ();
+
+        fn start(&mut self, _world: &mut World, _delta_time: f64) {
+            self.record("start");
+        }
+
+        fn on_enter(&mut self, _world: &mut World) {
+            self.record("enter");
+        }
+
+        fn on_exit(&mut self, _world: &mut World) {
+            self.record("exit");
+        }
+    }
+
+    fn world<'a, 'b>() -> World<'a, 'b> {
+        World {
+            system_executor: SystemExecutor::new().build(),
+            ecs_world: specs::prelude::World::new(),
+            connections: ConnectionCollection::new()
+        }
+    }
+
+    fn take(log: &Arc<Mutex<Vec<String>>>) -> Vec<String> {
+        log.lock().unwrap().drain(..).collect()
+    }
+
+    #[test]
+    fn push_and_pop_call_hooks_on_both_states() {
+        let (log, mut world) = (Arc::new(Mutex::new(vec!())), world());
+        let mut stack = StateStack::new(Recorder::new("lobby", &log) as Box<MasterController<ObserverEvent=()>>);
+
+        stack.apply(Transition::Push(Recorder::new("pause", &log)), &mut world).unwrap();
+        assert_eq!(take(&log), vec!("lobby exit", "pause start", "pause enter"));
+        assert_eq!(stack.depth(), 2);
+
+        stack.apply(Transition::Pop, &mut world).unwrap();
+        assert_eq!(take(&log), vec!("pause exit", "lobby enter"));
+        assert_eq!(stack.depth(), 1);
+    }
+
+    #[test]
+    fn switch_starts_the_new_state() {
+        let (log, mut world) = (Arc::new(Mutex::new(vec!())), world());
+        let mut stack = StateStack::new(Recorder::new("lobby", &log) as Box<MasterController<ObserverEvent=()>>);
+
+        stack.apply(Transition::Switch(Recorder::new("match", &log)), &mut world).unwrap();
+        assert_eq!(take(&log), vec!("lobby exit", "match start", "match enter"));
+        assert_eq!(stack.depth(), 1);
+    }
+
+    #[test]
+    fn last_state_cannot_be_popped() {
+        let (log, mut world) = (Arc::new(Mutex::new(vec!())), world());
+        let mut stack = StateStack::new(Recorder::new("lobby", &log) as Box<MasterController<ObserverEvent=()>>);
+
+        assert!(stack.apply(Transition::Pop, &mut world).is_err());
+        assert!(take(&log).is_empty());
+        assert_eq!(stack.depth(), 1);
+    }
+}
@@ -1,35 +1,177 @@
-use specs::prelude::{Dispatcher, DispatcherBuilder, System};
+use specs::prelude::{Dispatcher, DispatcherBuilder, System, Resources};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The stages a tick is split into, in the order they run. Every stage has its own
+/// dispatcher, so dependencies can only name systems in the same stage, and the world is
+/// maintained between stages so entities created in one are visible to the next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// The engine's own systems that the game's systems rely on, like timers and input
+    /// states. Games don't usually need to add systems here.
+    Engine,
+    PreUpdate,
+    Update,
+    PostUpdate,
+    View
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [Stage::Engine, Stage::PreUpdate, Stage::Update, Stage::PostUpdate, Stage::View];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
 
 pub struct SystemExecutor<'a, 'b> {
-    dispatcher: Dispatcher<'a, 'b>
+    dispatchers: Vec<Dispatcher<'a, 'b>>,
+    criteria: Vec<Vec<RunCriterion>>,
+    groups: HashMap<String, Arc<AtomicBool>>
 }
 
 pub struct SystemExecutorBuilder<'a, 'b> {
-    dispatcher_builder: DispatcherBuilder<'a, 'b>
+    dispatcher_builders: Vec<DispatcherBuilder<'a, 'b>>,
+    criteria: Vec<Vec<RunCriterion>>,
+    stage: Stage,
+    groups: HashMap<String, Arc<AtomicBool>>
+}
+
+// Run criteria are checked on the engine thread right before their stage is dispatched,
+// and open or close the gate of the system they belong to.
+struct RunCriterion {
+    check: Box<Fn(&Resources) -> bool>,
+    gate: Arc<AtomicBool>
+}
+
+// Systems can't be taken out of a built dispatcher, so a system that can be switched
+// off is wrapped and skips its own run while any of its gates are closed.
+struct GatedSystem<S> {
+    system: S,
+    gates: Vec<Arc<AtomicBool>>
+}
+
+impl<'a, S: System<'a>> System<'a> for GatedSystem<S> {
+    type SystemData = S::SystemData;
+
+    fn run(&mut self, data: Self::SystemData) {
+        if self.gates.iter().all(|gate| gate.load(Ordering::Relaxed)) {
+            self.system.run(data);
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        self.system.setup(res);
+    }
 }
 
 impl<'a, 'b> SystemExecutor<'a, 'b> {
     pub fn new() -> SystemExecutorBuilder<'a, 'b> {
         SystemExecutorBuilder {
-            dispatcher_builder: DispatcherBuilder::new()
+            dispatcher_builders: Stage::ALL.iter().map(|_| DispatcherBuilder::new()).collect(),
+            criteria: Stage::ALL.iter().map(|_| vec!()).collect(),
+            stage: Stage::Update,
+            groups: HashMap::new()
+        }
+    }
+
+    pub fn setup(&mut self, world: &mut specs::World) {
+        for dispatcher in &mut self.dispatchers {
+            dispatcher.setup(&mut world.res);
         }
     }
     
     pub fn run(&mut self, world: &mut specs::World) {
-        self.dispatcher.dispatch(&world.res);
+        for (dispatcher, criteria) in self.dispatchers.iter_mut().zip(&self.criteria) {
+            for criterion in criteria {
+                criterion.gate.store((criterion.check)(&world.res), Ordering::Relaxed);
+            }
+            dispatcher.dispatch(&world.res);
+            world.maintain();
+        }
+    }
+
+    /// Turns every system in `group` on or off. Unknown groups are ignored.
+    pub fn set_group_enabled(&mut self, group: &str, enabled: bool) {
+        if let Some(gate) = self.groups.get(group) {
+            gate.store(enabled, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_group_enabled(&self, group: &str) -> bool {
+        self.groups.get(group)
+            .map(|gate| gate.load(Ordering::Relaxed))
+            .unwrap_or(false)
     }
 }
 
 impl<'a, 'b> SystemExecutorBuilder<'a, 'b> {
+
+    /// Systems added after this go into `stage`. Systems go into `Stage::Update` by default.
+    pub fn set_stage(&mut self, stage: Stage) {
+        self.stage = stage;
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    fn dispatcher_builder(&mut self) -> &mut DispatcherBuilder<'a, 'b> {
+        &mut self.dispatcher_builders[self.stage.index()]
+    }
     
     pub fn add_system<S>(&mut self, system: S, name: &str, dep: &[&str])
     where
         S: for<'c> System<'c> + Send + 'a {
-        self.dispatcher_builder.add(system, name, dep);
+        self.dispatcher_builder().add(system, name, dep);
     }
-    pub fn build(mut self) -> SystemExecutor<'a, 'b> {
+
+    /// Adds a system that only runs while `group` is enabled. Groups start out enabled.
+    pub fn add_grouped_system<S>(&mut self, system: S, name: &str, dep: &[&str], group: &str)
+    where
+        S: for<'c> System<'c> + Send + 'a {
+        let gate = self.groups.entry(group.to_string())
+            .or_insert_with(|| Arc::new(AtomicBool::new(true)))
+            .clone();
+        self.dispatcher_builder().add(GatedSystem { system, gates: vec!(gate) }, name, dep);
+    }
+
+    /// Adds a system that only runs on ticks where `criterion` holds for the resource `R`.
+    /// The system is skipped while `R` doesn't exist.
+    pub fn add_system_run_if<S, R, F>(&mut self, system: S, name: &str, dep: &[&str], criterion: F)
+    where
+        S: for<'c> System<'c> + Send + 'a,
+        R: Send + Sync + 'static,
+        F: Fn(&R) -> bool + 'static {
+        let gate = Arc::new(AtomicBool::new(false));
+        self.criteria[self.stage.index()].push(RunCriterion {
+            check: Box::new(move |res: &Resources| {
+                res.try_fetch::<R>().map(|r| criterion(&*r)).unwrap_or(false)
+            }),
+            gate: gate.clone()
+        });
+        self.dispatcher_builder().add(GatedSystem { system, gates: vec!(gate) }, name, dep);
+    }
+
+    /// Adds a system that runs on the engine thread once the stage's parallel systems have
+    /// finished, so it doesn't have to be `Send`. Thread-local systems run in the order added.
+    pub fn add_thread_local<S>(&mut self, system: S)
+    where
+        S: for<'c> System<'c> + 'b {
+        self.dispatcher_builder().add_thread_local(system);
+    }
+
+    /// Systems added after a barrier only run once every system before it has finished.
+    pub fn add_barrier(&mut self) {
+        self.dispatcher_builder().add_barrier();
+    }
+
+    pub fn build(self) -> SystemExecutor<'a, 'b> {
         SystemExecutor {
-            dispatcher: self.dispatcher_builder.build()
+            dispatchers: self.dispatcher_builders.into_iter().map(|builder| builder.build()).collect(),
+            criteria: self.criteria,
+            groups: self.groups
         }
     }
-}
\ No newline at end of file
+}
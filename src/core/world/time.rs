@@ -0,0 +1,27 @@
+/// Where the simulation is up to. The engine updates this at the start of every tick, and
+/// every view sent to clients is stamped with the tick and time it was made on.
+#[derive(Clone, Debug)]
+pub struct ServerTime {
+    pub tick: u64,
+    /// Seconds since the last tick.
+    pub delta: f64,
+    /// Seconds since the server started, on the same clock clients sync to with pings.
+    pub time: f64,
+    /// The length of a fixed step in seconds, for systems that simulate in fixed steps
+    /// whatever `delta` is. One over the tick rate, see `EngineBuilder::with_tick_rate`.
+    pub fixed_delta: f64
+}
+
+/// The tick rate `fixed_delta` is based on when the engine isn't given one.
+pub const DEFAULT_TICK_RATE: f64 = 60.0;
+
+impl Default for ServerTime {
+    fn default() -> Self {
+        ServerTime {
+            tick: 0,
+            delta: 0.0,
+            time: 0.0,
+            fixed_delta: 1.0 / DEFAULT_TICK_RATE
+        }
+    }
+}
@@ -3,6 +3,7 @@
 #![feature(trait_alias)]
 
 extern crate specs;
+extern crate shrev;
 #[macro_use]
 extern crate shred_derive;
 #[macro_use]
@@ -15,11 +16,20 @@ extern crate serde;
 extern crate serde_derive;
 extern crate bytes;
 extern crate serde_json;
+extern crate roxmltree;
+extern crate base64;
+extern crate flate2;
 
 pub mod core;
 pub mod utils;
 pub mod systems;
 pub mod components;
+pub mod spatial;
+pub mod assets;
+pub mod tiles;
+pub mod lighting;
+pub mod procgen;
+pub mod physics;
 
 pub use specs::prelude::*;
 
@@ -0,0 +1,467 @@
+use crate::components::{Camera, Position};
+use crate::utils::*;
+use rayon::prelude::*;
+use specs::prelude::*;
+use std::collections::HashMap;
+
+pub type Color = (f32, f32, f32);
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LightSource {
+    pub color: Color,
+    /// In world units. Nothing past this is lit.
+    pub radius: f32,
+    /// How sharply the light fades towards `radius`; 1.0 fades linearly.
+    #[serde(default = "default_falloff")]
+    pub falloff: f32
+}
+
+fn default_falloff() -> f32 {
+    1.0
+}
+
+/// Blocks light from reaching the cells behind the cell it's in. The cell itself is still lit.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Occluder {}
+
+crate::define_component!(LightSource);
+crate::define_component!(Occluder);
+
+/// The area lit, as a grid of `width` by `height` cells of `cell_size` world units starting at
+/// the origin, and the light every cell gets without any light source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightingConfig {
+    pub cell_size: f32,
+    pub width: u32,
+    pub height: u32,
+    pub ambient: Color
+}
+
+impl Default for LightingConfig {
+    fn default() -> Self {
+        LightingConfig {
+            cell_size: 32.0,
+            width: 0,
+            height: 0,
+            ambient: (0.0, 0.0, 0.0)
+        }
+    }
+}
+
+/// The light level of every cell, worked out by `LightingSystem` whenever a light, occluder
+/// or the `LightingConfig` changes. Channels are clamped to 0.0..=1.0.
+#[derive(Clone, Debug, Default)]
+pub struct LightMap {
+    width: u32,
+    height: u32,
+    cell_size: f32,
+    levels: Vec<Color>
+}
+
+impl LightMap {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    /// The cell a world position is in, or `None` if it's outside the lit area.
+    pub fn cell_of(&self, x: f32, y: f32) -> Option<(u32, u32)> {
+        if x < 0.0 || y < 0.0 || self.cell_size <= 0.0 {
+            return None;
+        }
+        let cell = ((x / self.cell_size) as u32, (y / self.cell_size) as u32);
+        if cell.0 < self.width && cell.1 < self.height {
+            Some(cell)
+        } else {
+            None
+        }
+    }
+
+    pub fn level(&self, x: u32, y: u32) -> Option<Color> {
+        if x < self.width && y < self.height {
+            Some(self.levels[(y * self.width + x) as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn level_at(&self, x: f32, y: f32) -> Option<Color> {
+        self.cell_of(x, y).and_then(|(cx, cy)| self.level(cx, cy))
+    }
+
+    /// The cells from `min` to `max` (inclusive, in world units), clipped to the lit area.
+    /// `None` if the region is entirely outside it.
+    pub fn view(&self, min: (f32, f32), max: (f32, f32)) -> Option<LightView> {
+        if self.width == 0 || self.height == 0 || self.cell_size <= 0.0 {
+            return None;
+        }
+        let (right, bottom) = (self.width as f32 * self.cell_size, self.height as f32 * self.cell_size);
+        // Written so a NaN corner counts as no overlap
+        if !(max.0 >= 0.0 && max.1 >= 0.0 && min.0 < right && min.1 < bottom && min.0 <= max.0 && min.1 <= max.1) {
+            return None;
+        }
+        let clip = |v: f32, cells: u32| ((v / self.cell_size).max(0.0) as u32).min(cells - 1);
+        let (x0, y0) = (clip(min.0, self.width), clip(min.1, self.height));
+        let (x1, y1) = (clip(max.0, self.width), clip(max.1, self.height));
+
+        let mut levels = vec!();
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let (r, g, b) = self.levels[(y * self.width + x) as usize];
+                levels.push(((r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8));
+            }
+        }
+        Some(LightView {
+            x: x0,
+            y: y0,
+            width: x1 - x0 + 1,
+            height: y1 - y0 + 1,
+            cell_size: self.cell_size,
+            levels
+        })
+    }
+}
+
+/// The light levels of the cells around a client's camera, from 0 to 255 per channel.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LightView {
+    /// In cells.
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub cell_size: f32,
+    /// Row by row, `width` by `height`.
+    pub levels: Vec<(u8, u8, u8)>
+}
+
+#[derive(PartialEq)]
+struct Light {
+    cell: (i64, i64),
+    position: (f32, f32),
+    color: Color,
+    radius: f32,
+    falloff: f32
+}
+
+// Whether nothing blocks the line between two cells, not counting the cells at either end.
+// Cells off the map never block.
+fn line_of_sight(occluded: &[bool], (width, height): (i64, i64), from: (i64, i64), to: (i64, i64)) -> bool {
+    let (dx, dy) = ((to.0 - from.0).abs(), -(to.1 - from.1).abs());
+    let (sx, sy) = (if from.0 < to.0 { 1 } else { -1 }, if from.1 < to.1 { 1 } else { -1 });
+    let (mut x, mut y) = from;
+    let mut err = dx + dy;
+    loop {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        if (x, y) == to {
+            return true;
+        }
+        if x >= 0 && y >= 0 && x < width && y < height && occluded[(y * width + x) as usize] {
+            return false;
+        }
+    }
+}
+
+// Lights every cell of the `LightingConfig` grid from the `LightSource`s that can see it,
+// splitting the rows between the rayon pool. Light from several sources adds up. Each light
+// only looks at the cells within its radius, and the map is only worked out again when a
+// light, an occluder or the config is different from last time.
+pub struct LightingSystem {
+    last: Option<(LightingConfig, Vec<Light>, Vec<(i64, i64)>)>
+}
+
+impl LightingSystem {
+    pub fn new() -> Self {
+        LightingSystem {
+            last: None
+        }
+    }
+}
+
+impl<'a> System<'a> for LightingSystem {
+    type SystemData = (Read<'a, LightingConfig>,
+    Write<'a, LightMap>,
+    ReadStorage<'a, Position>,
+    ReadStorage<'a, LightSource>,
+    ReadStorage<'a, Occluder>);
+
+    fn run(&mut self, (config, mut map, positions, sources, occluders): Self::SystemData) {
+        let (width, height, cell_size) = (config.width as i64, config.height as i64, config.cell_size);
+        if width == 0 || height == 0 || cell_size <= 0.0 {
+            return;
+        }
+        let cell_of = |x: f32, y: f32| ((x / cell_size).floor() as i64, (y / cell_size).floor() as i64);
+        let inside = |(x, y): (i64, i64)| x >= 0 && y >= 0 && x < width && y < height;
+
+        let mut occluder_cells: Vec<(i64, i64)> = (&positions, &occluders).join()
+            .map(|(p, _)| cell_of(p.x, p.y))
+            .filter(|cell| inside(*cell))
+            .collect();
+        occluder_cells.sort();
+        occluder_cells.dedup();
+
+        // Lights off the map still light the cells their radius reaches
+        let (right, bottom) = (width as f32 * cell_size, height as f32 * cell_size);
+        let lights: Vec<Light> = (&positions, &sources).join()
+            .filter(|(p, source)| p.x + source.radius > 0.0 && p.y + source.radius > 0.0
+                && p.x - source.radius < right && p.y - source.radius < bottom)
+            .map(|(p, source)| Light {
+                cell: cell_of(p.x, p.y),
+                position: (p.x, p.y),
+                color: source.color,
+                radius: source.radius,
+                falloff: source.falloff
+            })
+            .collect();
+
+        let inputs = (config.clone(), lights, occluder_cells);
+        if self.last.as_ref() == Some(&inputs) {
+            return;
+        }
+        let (_, ref lights, ref occluder_cells) = inputs;
+
+        let mut occluded = vec![false; (width * height) as usize];
+        for &(x, y) in occluder_cells {
+            occluded[(y * width + x) as usize] = true;
+        }
+
+        let ambient = config.ambient;
+        let mut levels = vec![ambient; (width * height) as usize];
+        levels.par_chunks_mut(width as usize).enumerate().for_each(|(y, row)| {
+            let y = y as i64;
+            let center_y = (y as f32 + 0.5) * cell_size;
+            for light in lights {
+                if (center_y - light.position.1).abs() >= light.radius {
+                    continue;
+                }
+                let min_x = cell_of(light.position.0 - light.radius, 0.0).0.max(0);
+                let max_x = cell_of(light.position.0 + light.radius, 0.0).0.min(width - 1);
+                for x in min_x..=max_x {
+                    let center = ((x as f32 + 0.5) * cell_size, center_y);
+                    let distance = ((center.0 - light.position.0).powi(2) + (center.1 - light.position.1).powi(2)).sqrt();
+                    if distance >= light.radius {
+                        continue;
+                    }
+                    if light.cell != (x, y) && !line_of_sight(&occluded, (width, height), light.cell, (x, y)) {
+                        continue;
+                    }
+                    let strength = (1.0 - distance / light.radius).powf(light.falloff);
+                    let level = &mut row[x as usize];
+                    level.0 += light.color.0 * strength;
+                    level.1 += light.color.1 * strength;
+                    level.2 += light.color.2 * strength;
+                }
+            }
+            for level in row.iter_mut() {
+                *level = (level.0.min(1.0).max(0.0), level.1.min(1.0).max(0.0), level.2.min(1.0).max(0.0));
+            }
+        });
+
+        *map = LightMap {
+            width: config.width,
+            height: config.height,
+            cell_size,
+            levels
+        };
+        self.last = Some(inputs);
+    }
+}
+
+// Sends every client the light levels around their camera, as far as the camera's
+// `view_range` reaches. A client is only sent a new `LightView` when it differs from the last
+// one it was sent, and only connections that already have a view this tick are sent one.
+pub struct LightSyncSystem {
+    sent: HashMap<String, LightView>
+}
+
+impl LightSyncSystem {
+    pub fn new() -> Self {
+        LightSyncSystem {
+            sent: HashMap::new()
+        }
+    }
+}
+
+impl<'a> System<'a> for LightSyncSystem {
+    type SystemData = (Read<'a, LightMap>,
+    ReadConnections<'a>,
+    ReadStorage<'a, Camera>,
+    ReadStorage<'a, Position>,
+    WriteViewMap<'a>);
+
+    fn run(&mut self, (map, connections, cameras, positions, mut views): Self::SystemData) {
+        // Keys that reconnect are sent their light again
+        self.sent.retain(|key, _| connections.connections.iter().any(|conn| conn.key == *key));
+
+        let regions: HashMap<&String, ((f32, f32), (f32, f32))> = (&cameras, &positions).join()
+            .map(|(camera, p)| {
+                let center = (p.x + camera.offset.0 as f32, p.y + camera.offset.1 as f32);
+                let range = camera.view_range as f32;
+                (&camera.key, ((center.0 - range, center.1 - range), (center.0 + range, center.1 + range)))
+            })
+            .collect();
+
+        for (key, view) in views.iter_mut() {
+            let (min, max) = match regions.get(key) {
+                Some(region) => *region,
+                None => continue
+            };
+            let light = match map.view(min, max) {
+                Some(light) => light,
+                None => continue
+            };
+            if self.sent.get(key) != Some(&light) {
+                self.sent.insert(key.clone(), light.clone());
+                view.light = Some(light);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> LightMap {
+        LightMap {
+            width: 4,
+            height: 3,
+            cell_size: 10.0,
+            levels: vec!((0.5, 0.5, 0.5); 12)
+        }
+    }
+
+    fn lit_world() -> (World, LightingSystem) {
+        let mut world = World::new();
+        world.add_resource(LightingConfig { cell_size: 10.0, width: 10, height: 1, ambient: (0.1, 0.1, 0.1) });
+        let mut system = LightingSystem::new();
+        System::setup(&mut system, &mut world.res);
+        (world, system)
+    }
+
+    fn light(radius: f32) -> LightSource {
+        LightSource { color: (1.0, 1.0, 1.0), radius, falloff: 1.0 }
+    }
+
+    fn red(world: &World, x: u32) -> f32 {
+        world.read_resource::<LightMap>().level(x, 0).unwrap().0
+    }
+
+    #[test]
+    fn light_fades_with_distance() {
+        let (mut world, mut system) = lit_world();
+        world.create_entity().with(Position::new(5.0, 5.0)).with(light(40.0)).build();
+        system.run_now(&world.res);
+        assert_eq!(red(&world, 0), 1.0);
+        assert!(red(&world, 1) > red(&world, 2) && red(&world, 2) > red(&world, 3));
+        assert!(red(&world, 3) > 0.1);
+        // Cell 4's centre is exactly 40 units away
+        assert_eq!(red(&world, 4), 0.1);
+        assert_eq!(red(&world, 9), 0.1);
+    }
+
+    #[test]
+    fn occluders_cast_shadows() {
+        let (mut world, mut system) = lit_world();
+        world.create_entity().with(Position::new(5.0, 5.0)).with(light(80.0)).build();
+        world.create_entity().with(Position::new(25.0, 5.0)).with(Occluder {}).build();
+        system.run_now(&world.res);
+        assert!(red(&world, 1) > 0.1);
+        assert!(red(&world, 2) > 0.1);
+        assert_eq!(red(&world, 3), 0.1);
+        assert_eq!(red(&world, 6), 0.1);
+    }
+
+    #[test]
+    fn lights_off_the_map_reach_onto_it() {
+        let (mut world, mut system) = lit_world();
+        world.create_entity().with(Position::new(-15.0, 5.0)).with(light(40.0)).build();
+        system.run_now(&world.res);
+        assert!(red(&world, 0) > 0.1);
+        assert!(red(&world, 1) > 0.1);
+        assert_eq!(red(&world, 2), 0.1);
+    }
+
+    #[test]
+    fn map_is_only_worked_out_when_something_changes() {
+        let (mut world, mut system) = lit_world();
+        let lamp = world.create_entity().with(Position::new(5.0, 5.0)).with(light(40.0)).build();
+        system.run_now(&world.res);
+        let lit = red(&world, 1);
+
+        *world.write_resource::<LightMap>() = map();
+        system.run_now(&world.res);
+        assert_eq!(world.read_resource::<LightMap>().width(), 4);
+
+        world.write_storage::<Position>().get_mut(lamp).unwrap().x = 95.0;
+        system.run_now(&world.res);
+        assert_eq!(red(&world, 9), 1.0);
+        assert_eq!(red(&world, 0), 0.1);
+
+        world.write_storage::<Position>().get_mut(lamp).unwrap().x = 5.0;
+        world.create_entity().with(Position::new(15.0, 5.0)).with(Occluder {}).build();
+        system.run_now(&world.res);
+        assert_eq!(red(&world, 1), lit);
+        assert_eq!(red(&world, 2), 0.1);
+    }
+
+    #[test]
+    fn light_views_are_sent_when_they_change() {
+        use crate::core::{ClientView, Connection, ConnectionCollection};
+
+        let (mut world, mut lighting) = lit_world();
+        let mut connections = ConnectionCollection::new();
+        connections.push(Connection { key: "p1".to_string() });
+        world.add_resource(connections);
+        let mut system = LightSyncSystem::new();
+        System::setup(&mut system, &mut world.res);
+        world.create_entity().with(Position::new(0.0, 0.0)).with(Camera { key: "p1".to_string(), view_range: 100, offset: (0, 0) }).build();
+        let lamp = world.create_entity().with(Position::new(5.0, 5.0)).with(light(40.0)).build();
+
+        let mut sync = |world: &World| {
+            lighting.run_now(&world.res);
+            world.write_resource::<ViewMap>().insert("p1".to_string(), ClientView::new());
+            system.run_now(&world.res);
+            world.write_resource::<ViewMap>().remove("p1").unwrap().light
+        };
+        assert!(sync(&world).is_some());
+        assert!(sync(&world).is_none());
+        assert!(sync(&world).is_none());
+        world.write_storage::<Position>().get_mut(lamp).unwrap().x = 50.0;
+        assert!(sync(&world).is_some());
+        assert!(sync(&world).is_none());
+    }
+
+    #[test]
+    fn views_are_clipped_to_the_map() {
+        let map = map();
+        let view = map.view((-15.0, 5.0), (15.0, 100.0)).unwrap();
+        assert_eq!((view.x, view.y, view.width, view.height), (0, 0, 2, 3));
+        assert_eq!(view.levels.len(), 6);
+    }
+
+    #[test]
+    fn views_outside_the_map_are_none() {
+        let map = map();
+        assert_eq!(map.view((-50.0, -50.0), (-1.0, -1.0)), None);
+        assert_eq!(map.view((40.0, 0.0), (60.0, 20.0)), None);
+        assert_eq!(map.view((0.0, 30.0), (20.0, 50.0)), None);
+        assert_eq!(map.view((20.0, 20.0), (10.0, 10.0)), None);
+        assert_eq!(map.view((::std::f32::NAN, 0.0), (10.0, 10.0)), None);
+    }
+}
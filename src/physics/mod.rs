@@ -0,0 +1,435 @@
+use crate::components::Position;
+use crate::core::ServerTime;
+use crate::spatial::Cell;
+use specs::prelude::*;
+use shrev::EventChannel;
+use std::collections::{HashMap, HashSet};
+
+mod trigger;
+
+pub use trigger::{Tags, TriggerZone, TriggerEvent, TriggerEvents, TriggerSystem};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Velocity {
+    #[serde(default)]
+    pub x: f32,
+    #[serde(default)]
+    pub y: f32
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Acceleration {
+    #[serde(default)]
+    pub x: f32,
+    #[serde(default)]
+    pub y: f32
+}
+
+/// Centred on the entity's `Position`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub enum Shape {
+    Aabb { half_width: f32, half_height: f32 },
+    Circle { radius: f32 }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Body {
+    /// Never moved, by collisions or by its `Velocity`.
+    Static,
+    /// Pushed out of anything it overlaps.
+    Dynamic
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Dynamic
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Collider {
+    pub shape: Shape,
+    #[serde(default)]
+    pub body: Body
+}
+
+crate::define_component!(Velocity);
+crate::define_component!(Acceleration);
+crate::define_component!(Collider);
+
+/// Two colliders started or stopped overlapping. The entity with the lower id comes first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CollisionEvent {
+    Started(Entity, Entity),
+    Stopped(Entity, Entity)
+}
+
+/// Every `CollisionEvent`, as a resource. Systems read it with a reader registered in their
+/// `setup`.
+pub type CollisionEvents = EventChannel<CollisionEvent>;
+
+#[derive(Clone, Debug)]
+pub struct PhysicsConfig {
+    /// The size of the cells of the broadphase grid. About the size of a typical collider
+    /// works best.
+    pub cell_size: f32,
+    /// The most fixed steps taken in one tick, so a slow tick doesn't make the next one
+    /// slower still. Time past that is dropped. 0 is taken as 1.
+    pub max_steps: u32
+}
+
+impl Default for PhysicsConfig {
+    fn default() -> Self {
+        PhysicsConfig {
+            cell_size: 64.0,
+            max_steps: 8
+        }
+    }
+}
+
+impl Shape {
+    fn half_extents(&self) -> (f32, f32) {
+        match *self {
+            Shape::Aabb { half_width, half_height } => (half_width, half_height),
+            Shape::Circle { radius } => (radius, radius)
+        }
+    }
+}
+
+// How far `b` has to move along a normal pointing from `a` to `b` to stop overlapping `a`
+fn penetration(a: (f32, f32), a_shape: Shape, b: (f32, f32), b_shape: Shape) -> Option<((f32, f32), f32)> {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let sign = |v: f32| if v < 0.0 { -1.0 } else { 1.0 };
+
+    match (a_shape, b_shape) {
+        (Shape::Circle { radius: ra }, Shape::Circle { radius: rb }) => {
+            let distance = (dx * dx + dy * dy).sqrt();
+            if distance >= ra + rb {
+                return None;
+            }
+            let normal = if distance > 0.0 { (dx / distance, dy / distance) } else { (1.0, 0.0) };
+            Some((normal, ra + rb - distance))
+        },
+        (Shape::Aabb { half_width, half_height }, Shape::Circle { radius }) => {
+            // The closest point on the box to the circle's centre
+            let (cx, cy) = (dx.max(-half_width).min(half_width), dy.max(-half_height).min(half_height));
+            if (cx, cy) == (dx, dy) {
+                // The centre is inside the box, so push out through the nearest side
+                let (px, py) = (half_width + radius - dx.abs(), half_height + radius - dy.abs());
+                return Some(if px < py { ((sign(dx), 0.0), px) } else { ((0.0, sign(dy)), py) });
+            }
+            let (ox, oy) = (dx - cx, dy - cy);
+            let distance = (ox * ox + oy * oy).sqrt();
+            if distance >= radius {
+                return None;
+            }
+            Some(((ox / distance, oy / distance), radius - distance))
+        },
+        (Shape::Circle { .. }, Shape::Aabb { .. }) => {
+            penetration(b, b_shape, a, a_shape).map(|((nx, ny), depth)| ((-nx, -ny), depth))
+        },
+        (Shape::Aabb { .. }, Shape::Aabb { .. }) => {
+            let ((aw, ah), (bw, bh)) = (a_shape.half_extents(), b_shape.half_extents());
+            let (px, py) = (aw + bw - dx.abs(), ah + bh - dy.abs());
+            if px <= 0.0 || py <= 0.0 {
+                return None;
+            }
+            Some(if px < py { ((sign(dx), 0.0), px) } else { ((0.0, sign(dy)), py) })
+        }
+    }
+}
+
+struct PhysicsBody {
+    entity: Entity,
+    position: (f32, f32),
+    velocity: (f32, f32),
+    collider: Option<Collider>
+}
+
+// Moves everything with a `Velocity` and pushes overlapping `Collider`s apart, in steps of
+// `ServerTime::fixed_delta` so the simulation doesn't depend on how long ticks take. Pairs
+// that might overlap are found with a uniform grid of its own, where each collider goes in
+// every cell its bounding box touches, so a big collider doesn't slow down the small ones
+// around it. A dynamic body that hits another dynamic body is pushed back half as far as one
+// that hits a static body.
+pub struct PhysicsSystem {
+    // Time that hasn't been simulated yet
+    accumulator: f64,
+    contacts: HashSet<(Entity, Entity)>
+}
+
+impl PhysicsSystem {
+    pub fn new() -> Self {
+        PhysicsSystem {
+            accumulator: 0.0,
+            contacts: HashSet::new()
+        }
+    }
+
+    fn step(&self, bodies: &mut Vec<PhysicsBody>, cell_size: f32, dt: f32) -> HashSet<(Entity, Entity)> {
+        for body in bodies.iter_mut() {
+            let dynamic = body.collider.as_ref().map(|c| c.body == Body::Dynamic).unwrap_or(true);
+            if dynamic {
+                body.position.0 += body.velocity.0 * dt;
+                body.position.1 += body.velocity.1 * dt;
+            }
+        }
+
+        // Broadphase
+        let cell = |v: f32| (v / cell_size).floor() as i32;
+        let mut cells: HashMap<Cell, Vec<usize>> = HashMap::new();
+        for (i, body) in bodies.iter().enumerate() {
+            if let Some(ref collider) = body.collider {
+                let (hw, hh) = collider.shape.half_extents();
+                for cx in cell(body.position.0 - hw)..=cell(body.position.0 + hw) {
+                    for cy in cell(body.position.1 - hh)..=cell(body.position.1 + hh) {
+                        cells.entry((cx, cy)).or_insert_with(Vec::new).push(i);
+                    }
+                }
+            }
+        }
+        let mut pairs: Vec<(usize, usize)> = cells.values()
+            .flat_map(|cell| cell.iter().enumerate()
+                .flat_map(move |(n, &i)| cell[n + 1..].iter().map(move |&j| (i.min(j), i.max(j)))))
+            .collect();
+        // Resolved in the same order every time
+        pairs.sort();
+        pairs.dedup();
+
+        let mut contacts = HashSet::new();
+        for (i, j) in pairs {
+            let (a, b) = (&bodies[i], &bodies[j]);
+            let (a_collider, b_collider) = (a.collider.clone().unwrap(), b.collider.clone().unwrap());
+            if a_collider.body == Body::Static && b_collider.body == Body::Static {
+                continue;
+            }
+            let ((nx, ny), depth) = match penetration(a.position, a_collider.shape, b.position, b_collider.shape) {
+                Some(hit) => hit,
+                None => continue
+            };
+            contacts.insert(if a.entity.id() < b.entity.id() { (a.entity, b.entity) } else { (b.entity, a.entity) });
+
+            let (a_share, b_share) = match (a_collider.body, b_collider.body) {
+                (Body::Static, _) => (0.0, 1.0),
+                (_, Body::Static) => (1.0, 0.0),
+                _ => (0.5, 0.5)
+            };
+            // Only the part of the velocity heading into the other body is removed
+            let relative = (bodies[j].velocity.0 - bodies[i].velocity.0) * nx + (bodies[j].velocity.1 - bodies[i].velocity.1) * ny;
+            let closing = relative.min(0.0);
+
+            let a = &mut bodies[i];
+            a.position.0 -= nx * depth * a_share;
+            a.position.1 -= ny * depth * a_share;
+            a.velocity.0 += nx * closing * a_share;
+            a.velocity.1 += ny * closing * a_share;
+            let b = &mut bodies[j];
+            b.position.0 += nx * depth * b_share;
+            b.position.1 += ny * depth * b_share;
+            b.velocity.0 -= nx * closing * b_share;
+            b.velocity.1 -= ny * closing * b_share;
+        }
+        contacts
+    }
+}
+
+impl<'a> System<'a> for PhysicsSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, ServerTime>,
+    Read<'a, PhysicsConfig>,
+    Write<'a, CollisionEvents>,
+    WriteStorage<'a, Position>,
+    WriteStorage<'a, Velocity>,
+    ReadStorage<'a, Acceleration>,
+    ReadStorage<'a, Collider>);
+
+    fn run(&mut self, (entities, time, config, mut events, mut positions, mut velocities, accelerations, colliders): Self::SystemData) {
+        let dt = time.fixed_delta;
+        if dt <= 0.0 {
+            return;
+        }
+        let max_steps = config.max_steps.max(1);
+        self.accumulator += time.delta;
+        let mut steps = 0;
+        while self.accumulator >= dt && steps < max_steps {
+            self.accumulator -= dt;
+            steps += 1;
+
+            for (velocity, acceleration) in (&mut velocities, &accelerations).join() {
+                velocity.x += acceleration.x * dt as f32;
+                velocity.y += acceleration.y * dt as f32;
+            }
+
+            let mut bodies: Vec<PhysicsBody> = (&entities, &positions, velocities.maybe(), colliders.maybe()).join()
+                .filter(|(_, _, velocity, collider)| velocity.is_some() || collider.is_some())
+                .map(|(entity, p, velocity, collider)| PhysicsBody {
+                    entity,
+                    position: (p.x, p.y),
+                    velocity: velocity.map(|v| (v.x, v.y)).unwrap_or((0.0, 0.0)),
+                    collider: collider.cloned()
+                })
+                .collect();
+
+            let contacts = self.step(&mut bodies, config.cell_size, dt as f32);
+
+            for body in bodies {
+                // Positions are flagged, and even fetching one to write to is an event, so ones
+                // that didn't move are left alone
+                let moved = positions.get(body.entity).map(|p| (p.x, p.y) != body.position).unwrap_or(false);
+                if moved {
+                    if let Some(p) = positions.get_mut(body.entity) {
+                        p.x = body.position.0;
+                        p.y = body.position.1;
+                    }
+                }
+                if let Some(v) = velocities.get_mut(body.entity) {
+                    v.x = body.velocity.0;
+                    v.y = body.velocity.1;
+                }
+            }
+
+            // Sorted so events come out in the same order every time
+            let mut stopped: Vec<(Entity, Entity)> = self.contacts.difference(&contacts).cloned().collect();
+            let mut started: Vec<(Entity, Entity)> = contacts.difference(&self.contacts).cloned().collect();
+            stopped.sort();
+            started.sort();
+            events.iter_write(stopped.into_iter().map(|(a, b)| CollisionEvent::Stopped(a, b)));
+            events.iter_write(started.into_iter().map(|(a, b)| CollisionEvent::Started(a, b)));
+            self.contacts = contacts;
+        }
+        if steps == max_steps {
+            self.accumulator = self.accumulator.min(dt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sim {
+        world: World,
+        system: PhysicsSystem,
+        reader: ReaderId<CollisionEvent>
+    }
+
+    impl Sim {
+        fn new(config: PhysicsConfig) -> Self {
+            let mut world = World::new();
+            world.add_resource(config);
+            world.add_resource(ServerTime { delta: 0.1, fixed_delta: 0.1, ..ServerTime::default() });
+            let mut system = PhysicsSystem::new();
+            System::setup(&mut system, &mut world.res);
+            let reader = world.write_resource::<CollisionEvents>().register_reader();
+            Sim { world, system, reader }
+        }
+
+        fn add(&mut self, x: f32, y: f32, shape: Shape, body: Body) -> Entity {
+            self.world.create_entity().with(Position::new(x, y)).with(Collider { shape, body }).build()
+        }
+
+        fn run(&mut self) -> Vec<CollisionEvent> {
+            self.system.run_now(&self.world.res);
+            self.world.read_resource::<CollisionEvents>().read(&mut self.reader).cloned().collect()
+        }
+
+        fn x(&self, entity: Entity) -> f32 {
+            self.world.read_storage::<Position>().get(entity).unwrap().x
+        }
+    }
+
+    fn circle(radius: f32) -> Shape {
+        Shape::Circle { radius }
+    }
+
+    fn aabb(half_width: f32, half_height: f32) -> Shape {
+        Shape::Aabb { half_width, half_height }
+    }
+
+    fn small_cells() -> PhysicsConfig {
+        PhysicsConfig { cell_size: 10.0, ..PhysicsConfig::default() }
+    }
+
+    #[test]
+    fn colliders_in_different_cells_collide() {
+        let mut sim = Sim::new(small_cells());
+        let a = sim.add(0.0, 0.0, circle(8.0), Body::Dynamic);
+        let b = sim.add(12.0, 0.0, circle(8.0), Body::Dynamic);
+        let wall = sim.add(100.0, 0.0, aabb(50.0, 5.0), Body::Static);
+        let c = sim.add(155.0, 0.0, circle(8.0), Body::Dynamic);
+
+        assert_eq!(sim.run(), vec!(CollisionEvent::Started(a, b), CollisionEvent::Started(wall, c)));
+        assert_eq!((sim.x(a), sim.x(b)), (-2.0, 14.0));
+        assert_eq!(sim.x(wall), 100.0);
+        assert_eq!(sim.x(c), 158.0);
+    }
+
+    #[test]
+    fn boxes_are_pushed_out_along_the_shallowest_axis() {
+        let mut sim = Sim::new(small_cells());
+        let floor = sim.add(0.0, 0.0, aabb(100.0, 5.0), Body::Static);
+        let crate_ = sim.add(20.0, -8.0, aabb(5.0, 5.0), Body::Dynamic);
+        assert_eq!(sim.run(), vec!(CollisionEvent::Started(floor, crate_)));
+        let positions = sim.world.read_storage::<Position>();
+        let p = positions.get(crate_).unwrap();
+        assert_eq!((p.x, p.y), (20.0, -10.0));
+    }
+
+    #[test]
+    fn circles_are_pushed_off_box_corners_and_sides() {
+        let mut sim = Sim::new(small_cells());
+        let wall = sim.add(0.0, 0.0, aabb(10.0, 10.0), Body::Static);
+        let corner = sim.add(12.0, 13.0, circle(5.0), Body::Dynamic);
+        let side = sim.add(-13.0, 0.0, circle(5.0), Body::Dynamic);
+        assert_eq!(sim.run(), vec!(CollisionEvent::Started(wall, corner), CollisionEvent::Started(wall, side)));
+
+        let positions = sim.world.read_storage::<Position>();
+        let corner = positions.get(corner).unwrap();
+        let distance = ((corner.x - 10.0).powi(2) + (corner.y - 10.0).powi(2)).sqrt();
+        assert!((distance - 5.0).abs() < 1e-4);
+        assert_eq!(positions.get(side).unwrap().x, -15.0);
+    }
+
+    #[test]
+    fn collisions_start_and_stop() {
+        let mut sim = Sim::new(small_cells());
+        let wall = sim.add(0.0, 0.0, aabb(5.0, 5.0), Body::Static);
+        let ball = sim.add(18.0, 0.0, circle(5.0), Body::Dynamic);
+        sim.world.write_storage::<Velocity>().insert(ball, Velocity { x: -100.0, y: 0.0 }).unwrap();
+        sim.world.write_storage::<Acceleration>().insert(ball, Acceleration { x: -100.0, y: 0.0 }).unwrap();
+
+        assert_eq!(sim.run(), vec!(CollisionEvent::Started(wall, ball)));
+        assert_eq!(sim.x(ball), 10.0);
+        // Pushed against the wall, it stays touching
+        assert_eq!(sim.run(), vec!());
+        assert_eq!(sim.x(ball), 10.0);
+
+        sim.world.write_storage::<Acceleration>().remove(ball);
+        sim.world.write_storage::<Velocity>().insert(ball, Velocity { x: 100.0, y: 0.0 }).unwrap();
+        assert_eq!(sim.run(), vec!(CollisionEvent::Stopped(wall, ball)));
+        assert_eq!(sim.run(), vec!());
+    }
+
+    #[test]
+    fn slow_ticks_take_at_most_max_steps() {
+        let mut sim = Sim::new(PhysicsConfig { max_steps: 2, ..small_cells() });
+        let ball = sim.world.create_entity().with(Position::new(0.0, 0.0)).with(Velocity { x: 10.0, y: 0.0 }).build();
+        sim.world.write_resource::<ServerTime>().delta = 1.0;
+        sim.run();
+        assert_eq!(sim.x(ball), 2.0);
+        // What couldn't be simulated is dropped, apart from one step
+        sim.world.write_resource::<ServerTime>().delta = 0.0;
+        sim.run();
+        assert_eq!(sim.x(ball), 3.0);
+        sim.run();
+        assert_eq!(sim.x(ball), 3.0);
+    }
+
+    #[test]
+    fn zero_max_steps_still_steps() {
+        let mut sim = Sim::new(PhysicsConfig { max_steps: 0, ..small_cells() });
+        let ball = sim.world.create_entity().with(Position::new(0.0, 0.0)).with(Velocity { x: 10.0, y: 0.0 }).build();
+        sim.run();
+        assert_eq!(sim.x(ball), 1.0);
+    }
+}
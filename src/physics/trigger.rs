@@ -0,0 +1,247 @@
+use super::Shape;
+use crate::components::{Position, PositionTiled};
+use crate::spatial::SpatialIndex;
+use crate::tiles::GridConfig;
+use crate::utils::*;
+use specs::prelude::*;
+use shrev::EventChannel;
+use std::collections::{HashMap, HashSet};
+
+/// Labels an entity so trigger zones can pick it out.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Tags {
+    pub tags: Vec<String>
+}
+
+impl Tags {
+    pub fn has(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+/// An area centred on the entity's position that reports what's inside it, see
+/// `TriggerSystem`. With a `tag`, only entities whose `Tags` include it count.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TriggerZone {
+    pub shape: Shape,
+    #[serde(default)]
+    pub tag: Option<String>
+}
+
+crate::define_component!(Tags);
+crate::define_component!(TriggerZone);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerEvent {
+    Entered { zone: Entity, entity: Entity },
+    /// Sent every tick the entity is still inside, after the tick it entered.
+    Stayed { zone: Entity, entity: Entity },
+    /// Also sent when the entity or the zone is deleted.
+    Exited { zone: Entity, entity: Entity }
+}
+
+pub type TriggerEvents = EventChannel<TriggerEvent>;
+
+fn contains(shape: Shape, center: (f32, f32), point: (f32, f32)) -> bool {
+    let (dx, dy) = (point.0 - center.0, point.1 - center.1);
+    match shape {
+        Shape::Aabb { half_width, half_height } => dx.abs() <= half_width && dy.abs() <= half_height,
+        Shape::Circle { radius } => dx * dx + dy * dy <= radius * radius
+    }
+}
+
+// Works out which entities are inside each `TriggerZone` and publishes the changes in
+// `TriggerEvents`. An entity's `Position` is used if it has one; otherwise the centre of the
+// tile in its `PositionTiled`, going by `GridConfig`. Only an entity's position is tested,
+// not its collider. Entities with a `Position` are looked up in the `SpatialIndex`, so it
+// has to be kept up to date before this runs.
+pub struct TriggerSystem {
+    inside: HashMap<Entity, HashSet<Entity>>
+}
+
+impl TriggerSystem {
+    pub fn new() -> Self {
+        TriggerSystem {
+            inside: HashMap::new()
+        }
+    }
+}
+
+// Exits come before entries and stays, each in order of entity id so events come out in the
+// same order every time
+fn write_changes(events: &mut TriggerEvents, zone: Entity, before: &HashSet<Entity>, inside: &HashSet<Entity>) {
+    let sorted = |entities: Vec<&Entity>| {
+        let mut entities: Vec<Entity> = entities.into_iter().cloned().collect();
+        entities.sort_by_key(|entity| entity.id());
+        entities
+    };
+    for entity in sorted(before.difference(inside).collect()) {
+        events.single_write(TriggerEvent::Exited { zone, entity });
+    }
+    for entity in sorted(inside.iter().collect()) {
+        if before.contains(&entity) {
+            events.single_write(TriggerEvent::Stayed { zone, entity });
+        } else {
+            events.single_write(TriggerEvent::Entered { zone, entity });
+        }
+    }
+}
+
+impl<'a> System<'a> for TriggerSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, GridConfig>,
+    ReadSpatialIndex<'a>,
+    Write<'a, TriggerEvents>,
+    ReadStorage<'a, TriggerZone>,
+    ReadStorage<'a, Tags>,
+    ReadStorage<'a, Position>,
+    ReadStorage<'a, PositionTiled>);
+
+    fn run(&mut self, (entities, grid, index, mut events, zones, tags, positions, tiled_positions): Self::SystemData) {
+        let location = |entity: Entity| -> Option<(f32, f32)> {
+            match positions.get(entity) {
+                Some(p) => Some((p.x, p.y)),
+                None => tiled_positions.get(entity).map(|p| {
+                    let (x, y) = grid.to_world(p.x, p.y);
+                    (x + grid.tile_width / 2.0, y + grid.tile_height / 2.0)
+                })
+            }
+        };
+
+        // Entities only on the grid get an index of their own
+        let mut tiled = SpatialIndex::new(index.cell_size());
+        for (entity, _, _) in (&entities, &tiled_positions, !&positions).join() {
+            if let Some((x, y)) = location(entity) {
+                tiled.insert(entity, x, y);
+            }
+        }
+
+        let mut previous = ::std::mem::replace(&mut self.inside, HashMap::new());
+        let mut found: Vec<(Entity, HashSet<Entity>)> = vec!();
+        for (zone_entity, zone) in (&entities, &zones).join() {
+            let center = match location(zone_entity) {
+                Some(center) => center,
+                None => continue
+            };
+            let (hw, hh) = zone.shape.half_extents();
+            let (min, max) = ((center.0 - hw, center.1 - hh), (center.0 + hw, center.1 + hh));
+            // The index may be a little behind, so positions are checked again
+            let inside: HashSet<Entity> = index.query_rect(min, max).into_iter()
+                .filter(|entity| positions.contains(*entity))
+                .chain(tiled.query_rect(min, max))
+                .filter(|entity| {
+                    *entity != zone_entity
+                        && location(*entity).map(|point| contains(zone.shape, center, point)).unwrap_or(false)
+                        && zone.tag.as_ref().map(|tag| tags.get(*entity).map(|t| t.has(tag)).unwrap_or(false)).unwrap_or(true)
+                })
+                .collect();
+            found.push((zone_entity, inside));
+        }
+        found.sort_by_key(|(zone, _)| zone.id());
+
+        for (zone, inside) in found {
+            let before = previous.remove(&zone).unwrap_or_default();
+            write_changes(&mut events, zone, &before, &inside);
+            self.inside.insert(zone, inside);
+        }
+
+        // Zones that were deleted
+        let mut removed: Vec<(Entity, HashSet<Entity>)> = previous.into_iter().collect();
+        removed.sort_by_key(|(zone, _)| zone.id());
+        for (zone, before) in removed {
+            write_changes(&mut events, zone, &before, &HashSet::new());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::spatial::SpatialIndexSystem;
+
+    struct Zones {
+        world: World,
+        index: SpatialIndexSystem,
+        system: TriggerSystem,
+        reader: ReaderId<TriggerEvent>
+    }
+
+    impl Zones {
+        fn new() -> Self {
+            let mut world = World::new();
+            world.add_resource(SpatialIndex::new(10.0));
+            world.add_resource(GridConfig::new(10.0, 10.0));
+            let mut index = SpatialIndexSystem::new();
+            System::setup(&mut index, &mut world.res);
+            let mut system = TriggerSystem::new();
+            System::setup(&mut system, &mut world.res);
+            let reader = world.write_resource::<TriggerEvents>().register_reader();
+            Zones { world, index, system, reader }
+        }
+
+        fn run(&mut self) -> Vec<TriggerEvent> {
+            self.index.run_now(&self.world.res);
+            self.system.run_now(&self.world.res);
+            self.world.read_resource::<TriggerEvents>().read(&mut self.reader).cloned().collect()
+        }
+
+        fn move_to(&mut self, entity: Entity, x: f32) {
+            self.world.write_storage::<Position>().get_mut(entity).unwrap().x = x;
+        }
+    }
+
+    fn zone(tag: Option<&str>) -> TriggerZone {
+        TriggerZone { shape: Shape::Aabb { half_width: 20.0, half_height: 20.0 }, tag: tag.map(|t| t.to_string()) }
+    }
+
+    #[test]
+    fn entities_enter_stay_and_exit() {
+        let mut zones = Zones::new();
+        let area = zones.world.create_entity().with(Position::new(0.0, 0.0)).with(zone(None)).build();
+        let a = zones.world.create_entity().with(Position::new(50.0, 0.0)).build();
+        let b = zones.world.create_entity().with(Position::new(5.0, 5.0)).build();
+        assert_eq!(zones.run(), vec!(TriggerEvent::Entered { zone: area, entity: b }));
+
+        zones.move_to(a, 15.0);
+        assert_eq!(zones.run(), vec!(TriggerEvent::Entered { zone: area, entity: a }, TriggerEvent::Stayed { zone: area, entity: b }));
+
+        zones.move_to(a, 25.0);
+        zones.world.delete_entity(b).unwrap();
+        zones.world.maintain();
+        assert_eq!(zones.run(), vec!(TriggerEvent::Exited { zone: area, entity: a }, TriggerEvent::Exited { zone: area, entity: b }));
+        assert_eq!(zones.run(), vec!());
+    }
+
+    #[test]
+    fn deleted_zones_let_everything_out() {
+        let mut zones = Zones::new();
+        let area = zones.world.create_entity().with(Position::new(0.0, 0.0)).with(zone(None)).build();
+        let a = zones.world.create_entity().with(Position::new(5.0, 0.0)).build();
+        zones.run();
+        zones.world.delete_entity(area).unwrap();
+        zones.world.maintain();
+        assert_eq!(zones.run(), vec!(TriggerEvent::Exited { zone: area, entity: a }));
+    }
+
+    #[test]
+    fn tagged_zones_only_see_tagged_entities() {
+        let mut zones = Zones::new();
+        let area = zones.world.create_entity().with(Position::new(0.0, 0.0)).with(zone(Some("player"))).build();
+        zones.world.create_entity().with(Position::new(5.0, 0.0)).with(Tags { tags: vec!("bullet".to_string()) }).build();
+        zones.world.create_entity().with(Position::new(5.0, 0.0)).build();
+        let player = zones.world.create_entity().with(Position::new(-5.0, 0.0)).with(Tags { tags: vec!("player".to_string()) }).build();
+        assert_eq!(zones.run(), vec!(TriggerEvent::Entered { zone: area, entity: player }));
+    }
+
+    #[test]
+    fn zones_and_entities_can_be_on_the_grid() {
+        let mut zones = Zones::new();
+        // Centred on (35, 35)
+        let area = zones.world.create_entity().with(PositionTiled::new(3, 3)).with(zone(None)).build();
+        let tiled = zones.world.create_entity().with(PositionTiled::new(4, 4)).build();
+        zones.world.create_entity().with(PositionTiled::new(6, 3)).build();
+        let placed = zones.world.create_entity().with(Position::new(20.0, 50.0)).build();
+        zones.world.create_entity().with(Position::new(10.0, 35.0)).build();
+        assert_eq!(zones.run(), vec!(TriggerEvent::Entered { zone: area, entity: tiled }, TriggerEvent::Entered { zone: area, entity: placed }));
+    }
+}
@@ -0,0 +1,553 @@
+use crate::tiles::{TileID, TileMap};
+use std::collections::VecDeque;
+
+/// A small, fast random number generator (SplitMix64). The same seed always gives the same
+/// numbers on every platform, so maps generated from a seed are identical everywhere.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {
+            state: seed
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.state)
+    }
+
+    /// A number from 0.0 up to but not including 1.0.
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A number from `min` up to but not including `max`. Returns `min` if the range is empty.
+    pub fn range(&mut self, min: u32, max: u32) -> u32 {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min) as u64) as u32
+    }
+
+    pub fn chance(&mut self, probability: f32) -> bool {
+        self.next_f32() < probability
+    }
+
+    /// A new generator whose numbers don't depend on how many more this one hands out.
+    pub fn fork(&mut self) -> Rng {
+        Rng::new(self.next_u64())
+    }
+}
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Smooth 2D noise from -1.0 to 1.0.
+pub trait Noise {
+    fn get(&self, x: f32, y: f32) -> f32;
+
+    /// Several octaves of the noise added together, each `lacunarity` times finer and
+    /// `persistence` times weaker than the last. Stays within -1.0 to 1.0.
+    fn fractal(&self, x: f32, y: f32, octaves: u32, persistence: f32, lacunarity: f32) -> f32 {
+        let (mut total, mut amplitude, mut frequency, mut max) = (0.0, 1.0, 1.0, 0.0);
+        for _ in 0..octaves {
+            total += self.get(x * frequency, y * frequency) * amplitude;
+            max += amplitude;
+            amplitude *= persistence;
+            frequency *= lacunarity;
+        }
+        if max > 0.0 { total / max } else { 0.0 }
+    }
+}
+
+/// Random values at whole coordinates, smoothly blended in between.
+#[derive(Clone, Debug)]
+pub struct ValueNoise {
+    seed: u64
+}
+
+impl ValueNoise {
+    pub fn new(seed: u64) -> Self {
+        ValueNoise {
+            seed
+        }
+    }
+
+    fn value(&self, x: i64, y: i64) -> f32 {
+        let hash = mix(self.seed ^ mix((x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ y as u64));
+        (hash >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+impl Noise for ValueNoise {
+    fn get(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+        let (tx, ty) = (fade(x - x0 as f32), fade(y - y0 as f32));
+        let top = lerp(self.value(x0, y0), self.value(x0 + 1, y0), tx);
+        let bottom = lerp(self.value(x0, y0 + 1), self.value(x0 + 1, y0 + 1), tx);
+        lerp(top, bottom, ty)
+    }
+}
+
+/// Ken Perlin's improved gradient noise, with the permutation shuffled by the seed.
+#[derive(Clone)]
+pub struct PerlinNoise {
+    permutation: Vec<u8>
+}
+
+impl PerlinNoise {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut table: Vec<u8> = (0..=255).collect();
+        for i in (1..table.len()).rev() {
+            let j = rng.range(0, i as u32 + 1) as usize;
+            table.swap(i, j);
+        }
+        let mut permutation = table.clone();
+        permutation.extend(table);
+        PerlinNoise {
+            permutation
+        }
+    }
+
+    fn gradient(hash: u8, x: f32, y: f32) -> f32 {
+        match hash & 7 {
+            0 => x + y,
+            1 => x - y,
+            2 => -x + y,
+            3 => -x - y,
+            4 => x,
+            5 => -x,
+            6 => y,
+            _ => -y
+        }
+    }
+}
+
+impl Noise for PerlinNoise {
+    fn get(&self, x: f32, y: f32) -> f32 {
+        let (xf, yf) = (x.floor(), y.floor());
+        let (xi, yi) = ((xf as i64 & 255) as usize, (yf as i64 & 255) as usize);
+        let (x, y) = (x - xf, y - yf);
+        let (u, v) = (fade(x), fade(y));
+
+        let p = &self.permutation;
+        let (a, b) = (p[xi] as usize + yi, p[xi + 1] as usize + yi);
+        let top = lerp(PerlinNoise::gradient(p[a], x, y), PerlinNoise::gradient(p[b], x - 1.0, y), u);
+        let bottom = lerp(PerlinNoise::gradient(p[a + 1], x, y - 1.0), PerlinNoise::gradient(p[b + 1], x - 1.0, y - 1.0), u);
+        // Gradients can add up to a little past 1.0
+        (lerp(top, bottom, v) * 0.7071).max(-1.0).min(1.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+impl Rect {
+    pub fn center(&self) -> (u32, u32) {
+        (self.x + self.width / 2, self.y + self.height / 2)
+    }
+}
+
+// A generated map as a grid of walls and floors, before it's written to a tile map. All of
+// the generators build one, and it can be checked and fixed up before it's used.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub width: u32,
+    pub height: u32,
+    walls: Vec<bool>
+}
+
+impl Layout {
+    /// A layout that's all wall.
+    pub fn new(width: u32, height: u32) -> Self {
+        Layout {
+            width,
+            height,
+            walls: vec![true; (width * height) as usize]
+        }
+    }
+
+    /// Floor wherever the noise, sampled every `scale` tiles, is below `threshold`.
+    pub fn from_noise<N: Noise>(noise: &N, width: u32, height: u32, scale: f32, threshold: f32) -> Self {
+        let mut layout = Layout::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let value = noise.get(x as f32 / scale, y as f32 / scale);
+                layout.set_wall(x, y, value >= threshold);
+            }
+        }
+        layout
+    }
+
+    /// Everything outside the layout is wall.
+    pub fn is_wall(&self, x: i64, y: i64) -> bool {
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return true;
+        }
+        self.walls[(y as u32 * self.width + x as u32) as usize]
+    }
+
+    pub fn set_wall(&mut self, x: u32, y: u32, wall: bool) {
+        if x < self.width && y < self.height {
+            self.walls[(y * self.width + x) as usize] = wall;
+        }
+    }
+
+    pub fn carve(&mut self, room: &Rect) {
+        for y in room.y..room.y + room.height {
+            for x in room.x..room.x + room.width {
+                self.set_wall(x, y, false);
+            }
+        }
+    }
+
+    /// Carves a corridor one tile wide, going across first and then down or up.
+    pub fn carve_corridor(&mut self, from: (u32, u32), to: (u32, u32)) {
+        for x in from.0.min(to.0)..=from.0.max(to.0) {
+            self.set_wall(x, from.1, false);
+        }
+        for y in from.1.min(to.1)..=from.1.max(to.1) {
+            self.set_wall(to.0, y, false);
+        }
+    }
+
+    /// Every group of floor tiles that can reach each other moving up, down, left and right,
+    /// largest first. Regions of the same size are in the order they're found, scanning row
+    /// by row.
+    pub fn regions(&self) -> Vec<Vec<(u32, u32)>> {
+        let mut seen = vec![false; self.walls.len()];
+        let mut regions = vec!();
+        for start in 0..self.walls.len() {
+            if self.walls[start] || seen[start] {
+                continue;
+            }
+            let mut region = vec!();
+            let mut open = VecDeque::new();
+            seen[start] = true;
+            open.push_back(start);
+            while let Some(index) = open.pop_front() {
+                let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+                region.push((x, y));
+                for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if self.is_wall(nx, ny) {
+                        continue;
+                    }
+                    let next = (ny as u32 * self.width + nx as u32) as usize;
+                    if !seen[next] {
+                        seen[next] = true;
+                        open.push_back(next);
+                    }
+                }
+            }
+            regions.push(region);
+        }
+        // Stable, so ties keep the order they were found in
+        regions.sort_by(|a, b| b.len().cmp(&a.len()));
+        regions
+    }
+
+    /// Whether every floor tile can reach every other one.
+    pub fn is_connected(&self) -> bool {
+        self.regions().len() <= 1
+    }
+
+    /// Fills in every region but the largest, so the layout is connected.
+    pub fn keep_largest_region(&mut self) {
+        for region in self.regions().iter().skip(1) {
+            for &(x, y) in region {
+                self.set_wall(x, y, true);
+            }
+        }
+    }
+
+    /// Writes `wall` and `floor` tiles into a layer of the map, starting at its top left.
+    /// Tiles that don't fit in the map are left out.
+    pub fn write_to(&self, map: &mut TileMap, layer: usize, wall: TileID, floor: TileID) {
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let tile = if self.is_wall(x as i64, y as i64) { wall } else { floor };
+                map.set_tile(layer, x, y, tile);
+            }
+        }
+    }
+}
+
+// Caves grown by cellular automata: the layout starts as random noise, then every step a
+// tile becomes wall if at least `birth_limit` of its 8 neighbours are walls, or stays wall
+// if at least `survival_limit` are.
+#[derive(Clone, Debug)]
+pub struct CaveGenerator {
+    /// How much of the layout starts as wall.
+    pub fill: f32,
+    pub steps: u32,
+    pub birth_limit: u32,
+    pub survival_limit: u32
+}
+
+impl Default for CaveGenerator {
+    fn default() -> Self {
+        CaveGenerator {
+            fill: 0.45,
+            steps: 5,
+            birth_limit: 5,
+            survival_limit: 4
+        }
+    }
+}
+
+impl CaveGenerator {
+    pub fn generate(&self, rng: &mut Rng, width: u32, height: u32) -> Layout {
+        let mut layout = Layout::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let border = x == 0 || y == 0 || x + 1 == width || y + 1 == height;
+                layout.set_wall(x, y, border || rng.chance(self.fill));
+            }
+        }
+
+        for _ in 0..self.steps {
+            let mut next = layout.clone();
+            for y in 0..height {
+                for x in 0..width {
+                    let (x, y) = (x as i64, y as i64);
+                    let walls = (-1..=1)
+                        .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                        .filter(|&(dx, dy)| (dx, dy) != (0, 0) && layout.is_wall(x + dx, y + dy))
+                        .count() as u32;
+                    let wall = if layout.is_wall(x, y) {
+                        walls >= self.survival_limit
+                    } else {
+                        walls >= self.birth_limit
+                    };
+                    next.set_wall(x as u32, y as u32, wall);
+                }
+            }
+            layout = next;
+        }
+        layout
+    }
+}
+
+// Rooms and corridors from binary space partitioning: the layout is split in two again and
+// again, a room is put in each part that's left, and the rooms on either side of every split
+// are joined by a corridor, so every room can be reached.
+#[derive(Clone, Debug)]
+pub struct BspGenerator {
+    /// The smallest a part can be split into, walls included.
+    pub min_leaf: u32,
+    /// The smallest a room can be across or down.
+    pub min_room: u32,
+    pub max_depth: u32
+}
+
+impl Default for BspGenerator {
+    fn default() -> Self {
+        BspGenerator {
+            min_leaf: 8,
+            min_room: 4,
+            max_depth: 5
+        }
+    }
+}
+
+impl BspGenerator {
+    /// Returns the layout and the rooms carved into it.
+    pub fn generate(&self, rng: &mut Rng, width: u32, height: u32) -> (Layout, Vec<Rect>) {
+        let mut layout = Layout::new(width, height);
+        let mut rooms = vec!();
+        let area = Rect { x: 0, y: 0, width, height };
+        self.split(rng, area, 0, &mut layout, &mut rooms);
+        (layout, rooms)
+    }
+
+    // Returns the index of a room in this part of the layout, to join it to the other side
+    fn split(&self, rng: &mut Rng, area: Rect, depth: u32, layout: &mut Layout, rooms: &mut Vec<Rect>) -> Option<usize> {
+        let can_split_across = area.width >= self.min_leaf * 2;
+        let can_split_down = area.height >= self.min_leaf * 2;
+
+        if depth >= self.max_depth || !(can_split_across || can_split_down) {
+            return self.place_room(rng, area, layout, rooms);
+        }
+
+        // Split the long way, or at random when the part is roughly square
+        let across = match (can_split_across, can_split_down) {
+            (true, false) => true,
+            (false, true) => false,
+            _ => if area.width > area.height * 5 / 4 {
+                true
+            } else if area.height > area.width * 5 / 4 {
+                false
+            } else {
+                rng.chance(0.5)
+            }
+        };
+        let (first, second) = if across {
+            let at = rng.range(self.min_leaf, area.width - self.min_leaf + 1);
+            (Rect { width: at, ..area }, Rect { x: area.x + at, width: area.width - at, ..area })
+        } else {
+            let at = rng.range(self.min_leaf, area.height - self.min_leaf + 1);
+            (Rect { height: at, ..area }, Rect { y: area.y + at, height: area.height - at, ..area })
+        };
+
+        let a = self.split(rng, first, depth + 1, layout, rooms);
+        let b = self.split(rng, second, depth + 1, layout, rooms);
+        match (a, b) {
+            (Some(a), Some(b)) => {
+                let (from, to) = (rooms[a].center(), rooms[b].center());
+                layout.carve_corridor(from, to);
+                Some(if rng.chance(0.5) { a } else { b })
+            },
+            (a, b) => a.or(b)
+        }
+    }
+
+    fn place_room(&self, rng: &mut Rng, area: Rect, layout: &mut Layout, rooms: &mut Vec<Rect>) -> Option<usize> {
+        // Keep a wall between the room and the edge of its part
+        if area.width < self.min_room + 2 || area.height < self.min_room + 2 {
+            return None;
+        }
+        let width = rng.range(self.min_room, area.width - 2 + 1);
+        let height = rng.range(self.min_room, area.height - 2 + 1);
+        let room = Rect {
+            x: area.x + 1 + rng.range(0, area.width - 2 - width + 1),
+            y: area.y + 1 + rng.range(0, area.height - 2 - height + 1),
+            width,
+            height
+        };
+        layout.carve(&room);
+        rooms.push(room);
+        Some(rooms.len() - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn floor_count(layout: &Layout) -> usize {
+        layout.walls.iter().filter(|wall| !**wall).count()
+    }
+
+    #[test]
+    fn same_seed_gives_same_numbers() {
+        let (mut a, mut b) = (Rng::new(7), Rng::new(7));
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(Rng::new(7).next_u64(), Rng::new(8).next_u64());
+    }
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        for &(x, y) in &[(0.5, 0.25), (3.7, 12.1), (-4.2, 9.9)] {
+            assert_eq!(ValueNoise::new(3).get(x, y), ValueNoise::new(3).get(x, y));
+            assert_eq!(PerlinNoise::new(3).get(x, y), PerlinNoise::new(3).get(x, y));
+        }
+        assert_eq!(
+            Layout::from_noise(&PerlinNoise::new(11), 40, 30, 8.0, 0.0),
+            Layout::from_noise(&PerlinNoise::new(11), 40, 30, 8.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        let (value, perlin) = (ValueNoise::new(5), PerlinNoise::new(5));
+        for i in 0..1000 {
+            let (x, y) = (i as f32 * 0.37, i as f32 * 0.91);
+            for noise in &[value.get(x, y), perlin.get(x, y), perlin.fractal(x, y, 4, 0.5, 2.0)] {
+                assert!(*noise >= -1.0 && *noise <= 1.0, "{} out of range", noise);
+            }
+        }
+    }
+
+    #[test]
+    fn same_seed_gives_same_cave() {
+        let caves = CaveGenerator::default();
+        let a = caves.generate(&mut Rng::new(42), 64, 48);
+        let b = caves.generate(&mut Rng::new(42), 64, 48);
+        assert_eq!(a, b);
+        assert_ne!(a, caves.generate(&mut Rng::new(43), 64, 48));
+    }
+
+    #[test]
+    fn same_seed_gives_same_dungeon() {
+        let bsp = BspGenerator::default();
+        let (a, rooms_a) = bsp.generate(&mut Rng::new(42), 80, 60);
+        let (b, rooms_b) = bsp.generate(&mut Rng::new(42), 80, 60);
+        assert_eq!(a, b);
+        assert_eq!(rooms_a, rooms_b);
+    }
+
+    #[test]
+    fn same_seed_gives_same_tiles() {
+        let write = |seed| {
+            let mut map = TileMap::new(64, 48, 16);
+            map.add_layer("ground");
+            CaveGenerator::default().generate(&mut Rng::new(seed), 64, 48).write_to(&mut map, 0, 1, 2);
+            (0..48).flat_map(|y| (0..64).map(move |x| (x, y)))
+                .map(|(x, y)| map.get_tile(0, x, y))
+                .collect::<Vec<TileID>>()
+        };
+        assert_eq!(write(9), write(9));
+    }
+
+    #[test]
+    fn dungeon_rooms_are_connected() {
+        let bsp = BspGenerator::default();
+        for seed in 0..50 {
+            let (layout, rooms) = bsp.generate(&mut Rng::new(seed), 80, 60);
+            assert!(rooms.len() > 1, "seed {} made {} rooms", seed, rooms.len());
+            assert!(layout.is_connected(), "seed {} isn't connected", seed);
+
+            let region = &layout.regions()[0];
+            for room in &rooms {
+                assert!(region.contains(&room.center()), "seed {} left out room {:?}", seed, room);
+            }
+        }
+    }
+
+    #[test]
+    fn caves_are_connected_after_keeping_largest_region() {
+        for seed in 0..20 {
+            let mut layout = CaveGenerator::default().generate(&mut Rng::new(seed), 64, 48);
+            let largest = layout.regions().first().map(|region| region.len()).unwrap_or(0);
+            layout.keep_largest_region();
+            assert!(layout.is_connected());
+            assert_eq!(floor_count(&layout), largest);
+        }
+    }
+
+    #[test]
+    fn regions_split_on_walls() {
+        let mut layout = Layout::new(5, 3);
+        layout.carve(&Rect { x: 0, y: 0, width: 2, height: 3 });
+        layout.carve(&Rect { x: 3, y: 0, width: 2, height: 1 });
+        assert_eq!(layout.regions().iter().map(|r| r.len()).collect::<Vec<_>>(), vec!(6, 2));
+        assert!(!layout.is_connected());
+
+        layout.carve_corridor((1, 0), (3, 0));
+        assert!(layout.is_connected());
+    }
+}
@@ -0,0 +1,334 @@
+use crate::components::Position;
+use specs::prelude::*;
+use specs::world::Index;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+pub type Cell = (i32, i32);
+
+/// A uniform grid over every entity with a `Position`, kept up to date by
+/// `SpatialIndexSystem`. Positions are as of the last time that system ran.
+pub struct SpatialIndex {
+    cell_size: f32,
+    cells: HashMap<Cell, Vec<Entity>>,
+    entries: HashMap<Entity, (Cell, f32, f32)>
+}
+
+impl Default for SpatialIndex {
+    fn default() -> Self {
+        SpatialIndex::new(64.0)
+    }
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f32) -> Self {
+        SpatialIndex {
+            cell_size,
+            cells: HashMap::new(),
+            entries: HashMap::new()
+        }
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn cell_of(&self, x: f32, y: f32) -> Cell {
+        ((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32)
+    }
+
+    /// Adds `entity` or moves it if it's already indexed.
+    pub fn insert(&mut self, entity: Entity, x: f32, y: f32) {
+        let cell = self.cell_of(x, y);
+        if let Some(entry) = self.entries.get_mut(&entity) {
+            let old_cell = entry.0;
+            *entry = (cell, x, y);
+            if old_cell == cell {
+                return;
+            }
+            remove_from_cell(&mut self.cells, old_cell, entity);
+        } else {
+            self.entries.insert(entity, (cell, x, y));
+        }
+        self.cells.entry(cell).or_insert_with(Vec::new).push(entity);
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        if let Some((cell, _, _)) = self.entries.remove(&entity) {
+            remove_from_cell(&mut self.cells, cell, entity);
+        }
+    }
+
+    pub fn position(&self, entity: Entity) -> Option<(f32, f32)> {
+        self.entries.get(&entity).map(|&(_, x, y)| (x, y))
+    }
+
+    /// Every entity inside the rectangle from `min` to `max`, edges included.
+    pub fn query_rect(&self, min: (f32, f32), max: (f32, f32)) -> Vec<Entity> {
+        let (min_cell, max_cell) = (self.cell_of(min.0, min.1), self.cell_of(max.0, max.1));
+        let mut found = vec!();
+        for cx in min_cell.0..=max_cell.0 {
+            for cy in min_cell.1..=max_cell.1 {
+                for &entity in self.cells.get(&(cx, cy)).into_iter().flatten() {
+                    let (_, x, y) = self.entries[&entity];
+                    if x >= min.0 && x <= max.0 && y >= min.1 && y <= max.1 {
+                        found.push(entity);
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    /// Every entity within `radius` of `center`.
+    pub fn query_radius(&self, center: (f32, f32), radius: f32) -> Vec<Entity> {
+        self.query_rect((center.0 - radius, center.1 - radius), (center.0 + radius, center.1 + radius))
+            .into_iter()
+            .filter(|entity| {
+                let (_, x, y) = self.entries[entity];
+                distance_sq(center, (x, y)) <= radius * radius
+            })
+            .collect()
+    }
+
+    /// Up to `k` entities closest to `center`, nearest first. Entities at a NaN position
+    /// are never found.
+    pub fn nearest(&self, center: (f32, f32), k: usize) -> Vec<Entity> {
+        let origin = self.cell_of(center.0, center.1);
+        let mut found: Vec<(f32, Entity)> = vec!();
+        let mut seen = 0;
+        let mut searched_cells = 0;
+        let mut ring = 0;
+        // Search rings of cells outwards until nothing unsearched could be closer than
+        // the k-th entity found so far
+        while k > 0 && seen < self.entries.len() {
+            // Once the rings cover more cells than are in use, far away or non-finite
+            // positions are quicker to find by looking at everything
+            searched_cells += if ring == 0 { 1 } else { 8 * ring as usize };
+            if searched_cells > self.cells.len() {
+                found = self.entries.iter()
+                    .map(|(entity, &(_, x, y))| (distance_sq(center, (x, y)), *entity))
+                    .filter(|(distance, _)| !distance.is_nan())
+                    .collect();
+                break;
+            }
+            for cell in ring_cells(origin, ring) {
+                for &entity in self.cells.get(&cell).into_iter().flatten() {
+                    let (_, x, y) = self.entries[&entity];
+                    let distance = distance_sq(center, (x, y));
+                    if !distance.is_nan() {
+                        found.push((distance, entity));
+                    }
+                    seen += 1;
+                }
+            }
+            if found.len() >= k {
+                found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+                let searched = ring as f32 * self.cell_size;
+                if found[k - 1].0 <= searched * searched {
+                    break;
+                }
+            }
+            ring += 1;
+        }
+        found.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        found.into_iter().take(k).map(|(_, entity)| entity).collect()
+    }
+}
+
+fn remove_from_cell(cells: &mut HashMap<Cell, Vec<Entity>>, cell: Cell, entity: Entity) {
+    let now_empty = match cells.get_mut(&cell) {
+        Some(entities) => {
+            entities.retain(|e| *e != entity);
+            entities.is_empty()
+        },
+        None => false
+    };
+    if now_empty {
+        cells.remove(&cell);
+    }
+}
+
+fn ring_cells(origin: Cell, ring: i32) -> Vec<Cell> {
+    if ring == 0 {
+        return vec!(origin);
+    }
+    let mut cells = vec!();
+    // Cells past the edge of the grid are left out
+    let mut push = |dx: i32, dy: i32| if let (Some(x), Some(y)) = (origin.0.checked_add(dx), origin.1.checked_add(dy)) {
+        cells.push((x, y));
+    };
+    for dx in -ring..=ring {
+        if dx.abs() == ring {
+            for dy in -ring..=ring {
+                push(dx, dy);
+            }
+        } else {
+            push(dx, -ring);
+            push(dx, ring);
+        }
+    }
+    cells
+}
+
+fn distance_sq(a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - b.0) * (a.0 - b.0) + (a.1 - b.1) * (a.1 - b.1)
+}
+
+/// Moves entities between cells as their `Position` changes, and drops entities that died
+/// or lost their position. Only positions that were inserted, removed or written to since the
+/// last run are looked at.
+pub struct SpatialIndexSystem {
+    reader: Option<ReaderId<ComponentEvent>>,
+    // The entity indexed under each id, since removal events only carry the id
+    indexed: HashMap<Index, Entity>,
+    filled: bool
+}
+
+impl SpatialIndexSystem {
+    pub fn new() -> Self {
+        SpatialIndexSystem {
+            reader: None,
+            indexed: HashMap::new(),
+            filled: false
+        }
+    }
+}
+
+impl<'a> System<'a> for SpatialIndexSystem {
+    type SystemData = (Entities<'a>, ReadStorage<'a, Position>, Write<'a, SpatialIndex>);
+
+    fn run(&mut self, (entities, positions, mut index): Self::SystemData) {
+        let mut changed = BitSet::new();
+        let mut removed = BitSet::new();
+        for event in positions.channel().read(self.reader.as_mut().unwrap()) {
+            match *event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) => {
+                    changed.add(id);
+                    removed.remove(id);
+                },
+                ComponentEvent::Removed(id) => {
+                    removed.add(id);
+                    changed.remove(id);
+                }
+            }
+        }
+
+        // Positions from before the system was set up
+        if !self.filled {
+            self.filled = true;
+            for (entity, _) in (&entities, &positions).join() {
+                changed.add(entity.id());
+            }
+        }
+
+        for id in (&removed).join() {
+            if let Some(entity) = self.indexed.remove(&id) {
+                index.remove(entity);
+            }
+        }
+        for (entity, p, _) in (&entities, &positions, &changed).join() {
+            match self.indexed.insert(entity.id(), entity) {
+                // The id was reused without the old entity's position being removed first
+                Some(old) if old != entity => index.remove(old),
+                _ => {}
+            }
+            index.insert(entity, p.x, p.y);
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        <Self::SystemData as SystemData>::setup(res);
+        self.reader = Some(WriteStorage::<Position>::fetch(res).register_reader());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_skips_nan_positions() {
+        let mut world = World::new();
+        let (a, b, c) = (world.create_entity().build(), world.create_entity().build(), world.create_entity().build());
+        let mut index = SpatialIndex::new(10.0);
+        index.insert(a, 5.0, 5.0);
+        index.insert(b, ::std::f32::NAN, 1.0);
+        index.insert(c, 1.0, 1.0);
+        assert_eq!(index.nearest((0.0, 0.0), 3), vec!(c, a));
+    }
+
+    #[test]
+    fn nearest_finds_everything_when_k_is_large() {
+        let mut world = World::new();
+        let (a, b, c) = (world.create_entity().build(), world.create_entity().build(), world.create_entity().build());
+        let mut index = SpatialIndex::new(10.0);
+        index.insert(a, 35.0, 0.0);
+        index.insert(b, 12.0, 0.0);
+        index.insert(c, -3.0, 0.0);
+        assert_eq!(index.nearest((0.0, 0.0), 10), vec!(c, b, a));
+        assert_eq!(index.nearest((0.0, 0.0), 0), vec!());
+        assert_eq!(SpatialIndex::new(10.0).nearest((0.0, 0.0), 3), vec!());
+    }
+
+    #[test]
+    fn nearest_reaches_far_away_entities() {
+        let mut world = World::new();
+        let (near, far) = (world.create_entity().build(), world.create_entity().build());
+        let mut index = SpatialIndex::new(1.0);
+        index.insert(near, 1.0, 1.0);
+        index.insert(far, 1.0e9, -1.0e9);
+        assert_eq!(index.nearest((0.0, 0.0), 2), vec!(near, far));
+        assert_eq!(index.nearest((2.0e9, -2.0e9), 1), vec!(far));
+    }
+
+    #[test]
+    fn nearest_handles_non_finite_positions() {
+        let mut world = World::new();
+        let (a, b, c) = (world.create_entity().build(), world.create_entity().build(), world.create_entity().build());
+        let mut index = SpatialIndex::new(10.0);
+        index.insert(a, ::std::f32::NAN, ::std::f32::NAN);
+        index.insert(b, ::std::f32::INFINITY, 0.0);
+        index.insert(c, 0.0, ::std::f32::NEG_INFINITY);
+        let found = index.nearest((0.0, 0.0), 3);
+        assert_eq!(found.len(), 2);
+        assert!(!found.contains(&a));
+        assert_eq!(index.nearest((::std::f32::NAN, 0.0), 3), vec!());
+    }
+
+    #[test]
+    fn system_follows_position_changes() {
+        let mut world = World::new();
+        world.register::<Position>();
+        world.add_resource(SpatialIndex::new(10.0));
+        let mut system = SpatialIndexSystem::new();
+        System::setup(&mut system, &mut world.res);
+
+        let before = world.create_entity().with(Position::new(1.0, 1.0)).build();
+        system.run_now(&world.res);
+        let a = world.create_entity().with(Position::new(5.0, 5.0)).build();
+        let b = world.create_entity().with(Position::new(50.0, 50.0)).build();
+        system.run_now(&world.res);
+        assert_eq!(world.read_resource::<SpatialIndex>().len(), 3);
+        assert_eq!(world.read_resource::<SpatialIndex>().position(before), Some((1.0, 1.0)));
+
+        world.write_storage::<Position>().get_mut(b).unwrap().x = 6.0;
+        world.write_storage::<Position>().remove(before);
+        system.run_now(&world.res);
+        {
+            let index = world.read_resource::<SpatialIndex>();
+            assert_eq!(index.position(b), Some((6.0, 50.0)));
+            assert_eq!(index.position(before), None);
+        }
+
+        world.delete_entity(a).unwrap();
+        world.maintain();
+        system.run_now(&world.res);
+        assert_eq!(world.read_resource::<SpatialIndex>().position(a), None);
+        assert_eq!(world.read_resource::<SpatialIndex>().len(), 1);
+    }
+}
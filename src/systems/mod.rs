@@ -1,11 +1,15 @@
 use crate::components::*;
-use crate::core::ClientView;
+use crate::core::{ClientView, EntityView, ZLevels, NetworkIdAllocator};
 use crate::specs::prelude::*;
 use crate::utils::*;
+use std::collections::{HashMap, HashSet};
 
 pub struct ViewSystem {
     use_cameras: bool,
-    filter: Vec<String>
+    filter: Vec<String>,
+    z_levels: Vec<ZLevelID>,
+    // The network ids each connection was last sent
+    known: HashMap<String, HashSet<u64>>
 }
 
 
@@ -13,42 +17,222 @@ impl ViewSystem {
     pub fn new(use_cameras: bool, filter: Option<Vec<String>>) -> Self {
         ViewSystem {
             use_cameras,
-            filter: filter.unwrap_or(Vec::new())
+            filter: filter.unwrap_or(Vec::new()),
+            z_levels: Vec::new(),
+            known: HashMap::new()
         }
     }
+
+    /// Only send entities on these z-levels.
+    pub fn with_z_levels(mut self, z_levels: &[&str]) -> Self {
+        self.z_levels = z_levels.iter().map(|z| z.to_string()).collect();
+        self
+    }
+
+    fn shows(&self, p: &Position) -> bool {
+        self.z_levels.len() == 0 || self.z_levels.contains(&p.z_level)
+    }
+
+    // Fills in what entered and left the view since this connection's last view
+    fn track(&mut self, key: &String, view: &mut ClientView) {
+        let now: HashSet<u64> = view.entities.iter().map(|e| e.id).collect();
+        let before = self.known.entry(key.clone()).or_insert_with(HashSet::new);
+        view.spawned = now.difference(before).cloned().collect();
+        view.despawned = before.difference(&now).cloned().collect();
+        *before = now;
+    }
 }
 
 impl<'a> System<'a> for ViewSystem {
-    type SystemData = (ReadConnections<'a>,
+    type SystemData = (Entities<'a>,
+    ReadStorage<'a, NetworkId>,
+    ReadConnections<'a>,
     ReadStorage<'a, Camera>,
     ReadStorage<'a, Position>,
     ReadStorage<'a, Visible>,
-    WriteViewMap<'a>);
+    WriteViewMap<'a>,
+    Option<ReadSpatialIndex<'a>>,
+    Read<'a, ZLevels>,
+    (ReadStorage<'a, Rotation>,
+     ReadStorage<'a, Scale>,
+     ReadStorage<'a, Flip>,
+     ReadStorage<'a, Tint>,
+     ReadStorage<'a, Opacity>,
+     ReadStorage<'a, Frame>,
+     ReadStorage<'a, ZOrder>));
+
+    fn run(&mut self, (entities, network_ids, connections, cameras, positions, visible, mut views, index, z_levels, looks): Self::SystemData) {
+        let (rotations, scales, flips, tints, opacities, frames, z_orders) = looks;
+        let should_filter = self.filter.len() > 0;
+
+        // A key that reconnects starts over with nothing known
+        self.known.retain(|key, _| connections.connections.iter().any(|conn| conn.key == *key));
+
+        // Entities only get an id at the end of the tick they were created in
+        let record = |entity: Entity, p: &Position, v: &Visible| network_ids.get(entity).map(|net| EntityView {
+            z_order: z_orders.get(entity).map(|z| z.order),
+            rotation: rotations.get(entity).map(|r| r.angle),
+            scale: scales.get(entity).map(|s| (s.x, s.y)),
+            flip: flips.get(entity).map(|f| (f.horizontal, f.vertical)),
+            tint: tints.get(entity).map(|t| (t.r, t.g, t.b)),
+            opacity: opacities.get(entity).map(|o| o.alpha),
+            frame: frames.get(entity).map(|f| f.index),
+            ..EntityView::new(net.id, v.sprite, (p.x, p.y))
+        });
 
-    fn run(&mut self, (connections, cameras, positions, visible, mut views): Self::SystemData) {
         if self.use_cameras {
-            unimplemented!()
+            // Each camera gets its own view of whatever is in range of it
+            for (camera, cam_pos) in (&cameras, &positions).join() {
+                if should_filter {
+                    if !self.filter.contains(&camera.key) {
+                        continue;
+                    }
+                }
+                if !connections.connections.iter().any(|conn| conn.key == camera.key) {
+                    continue;
+                }
+
+                let center = (cam_pos.x + camera.offset.0 as f32, cam_pos.y + camera.offset.1 as f32);
+                let range = camera.view_range as f32;
+
+                let in_range = |p: &Position| (p.x - center.0).abs() <= range && (p.y - center.1).abs() <= range;
+
+                let mut view = ClientView::new();
+                if let Some(ref index) = index {
+                    // The index may be a stage behind, so positions are still checked
+                    let candidates = index.query_rect((center.0 - range, center.1 - range), (center.0 + range, center.1 + range));
+                    for entity in candidates {
+                        if let (Some(p), Some(v)) = (positions.get(entity), visible.get(entity)) {
+                            if in_range(p) && self.shows(p) {
+                                if let Some(entry) = record(entity, p, v) {
+                                    view.push(entry, z_levels.get(&p.z_level));
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    for (entity, p, v) in (&entities, &positions, &visible).join() {
+                        if in_range(p) && self.shows(p) {
+                            if let Some(entry) = record(entity, p, v) {
+                                view.push(entry, z_levels.get(&p.z_level));
+                            }
+                        }
+                    }
+                }
+                view.sort_layers();
+                self.track(&camera.key, &mut view);
+                views.insert(camera.key.clone(), view);
+            }
         } else {
             // Capture everything and load it into a single view
             let view = {
                 let mut view = ClientView::new();
-                for (p, v) in (&positions, &visible).join() {
-                    view.sprites.push(v.sprite);
-                    view.loc.push((p.x, p.y));
+                for (entity, p, v) in (&entities, &positions, &visible).join() {
+                    if self.shows(p) {
+                        if let Some(entry) = record(entity, p, v) {
+                            view.push(entry, z_levels.get(&p.z_level));
+                        }
+                    }
                 }
+                view.sort_layers();
                 view
             };
 
-            let should_filter = self.filter.len() > 0;
-
             for conn in &connections.connections {
                 if should_filter {
                     if !self.filter.contains(&conn.key) {
                         continue;
                     }
                 }
-                views.insert(conn.key.clone(), view.clone());
+                let mut view = view.clone();
+                self.track(&conn.key, &mut view);
+                views.insert(conn.key.clone(), view);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Gives every `Visible` entity that doesn't have one yet a `NetworkId`.
+pub struct NetworkIdSystem;
+
+impl<'a> System<'a> for NetworkIdSystem {
+    type SystemData = (Entities<'a>,
+    ReadStorage<'a, Visible>,
+    WriteStorage<'a, NetworkId>,
+    Write<'a, NetworkIdAllocator>);
+
+    fn run(&mut self, (entities, visible, mut network_ids, mut allocator): Self::SystemData) {
+        let missing: Vec<Entity> = (&entities, &visible, !&network_ids).join()
+            .map(|(entity, _, _)| entity)
+            .collect();
+        for entity in missing {
+            if network_ids.insert(entity, NetworkId { id: allocator.allocate() }).is_err() {
+                // Only dead entities can't be given one. The id they took is never used,
+                // which is fine since ids aren't reused anyway.
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Connection, ConnectionCollection};
+    use crate::spatial::SpatialIndex;
+
+    fn world() -> World {
+        let mut world = World::new();
+        let mut connections = ConnectionCollection::new();
+        connections.push(Connection { key: "p1".to_string() });
+        world.add_resource(connections);
+        world
+    }
+
+    fn tick(world: &mut World, system: &mut ViewSystem) -> ClientView {
+        NetworkIdSystem.run_now(&world.res);
+        system.run_now(&world.res);
+        world.maintain();
+        world.write_resource::<ViewMap>().remove("p1").unwrap()
+    }
+
+    #[test]
+    fn entities_spawn_once() {
+        let mut world = world();
+        let mut system = ViewSystem::new(false, None);
+        System::setup(&mut system, &mut world.res);
+        System::setup(&mut NetworkIdSystem, &mut world.res);
+
+        let a = world.create_entity().with(Position::new(0.0, 0.0)).with(Visible { sprite: 0 }).build();
+        let view = tick(&mut world, &mut system);
+        let id = world.read_storage::<NetworkId>().get(a).unwrap().id;
+        assert_eq!(view.spawned, vec!(id));
+        for _ in 0..3 {
+            let view = tick(&mut world, &mut system);
+            assert_eq!((view.entities.len(), view.spawned.len(), view.despawned.len()), (1, 0, 0));
+        }
+
+        world.delete_entity(a).unwrap();
+        let view = tick(&mut world, &mut system);
+        assert_eq!(view.despawned, vec!(id));
+    }
+
+    #[test]
+    fn cameras_only_see_what_the_index_has() {
+        let mut world = world();
+        let mut system = ViewSystem::new(true, None);
+        System::setup(&mut system, &mut world.res);
+        System::setup(&mut NetworkIdSystem, &mut world.res);
+        world.add_resource(SpatialIndex::new(10.0));
+
+        world.create_entity().with(Position::new(0.0, 0.0)).with(Camera { key: "p1".to_string(), view_range: 50, offset: (0, 0) }).build();
+        let seen = world.create_entity().with(Position::new(5.0, 5.0)).with(Visible { sprite: 0 }).build();
+        world.create_entity().with(Position::new(6.0, 6.0)).with(Visible { sprite: 0 }).build();
+        world.create_entity().with(Position::new(500.0, 5.0)).with(Visible { sprite: 0 }).build();
+        assert_eq!(tick(&mut world, &mut system).entities.len(), 0);
+
+        world.write_resource::<SpatialIndex>().insert(seen, 5.0, 5.0);
+        let view = tick(&mut world, &mut system);
+        assert_eq!(view.entities.len(), 1);
+        assert_eq!(view.entities[0].id, world.read_storage::<NetworkId>().get(seen).unwrap().id);
+    }
+}
@@ -0,0 +1,378 @@
+use super::TileMap;
+use crate::components::{Position, PositionTiled};
+use crate::core::ServerTime;
+use specs::prelude::*;
+use std::collections::VecDeque;
+
+/// The size of a tile in world units, used to convert between `Position` and `PositionTiled`.
+/// A tile's world position is its top left corner.
+#[derive(Clone, Debug)]
+pub struct GridConfig {
+    pub tile_width: f32,
+    pub tile_height: f32
+}
+
+impl Default for GridConfig {
+    fn default() -> Self {
+        GridConfig::new(32.0, 32.0)
+    }
+}
+
+impl GridConfig {
+    pub fn new(tile_width: f32, tile_height: f32) -> Self {
+        GridConfig {
+            tile_width,
+            tile_height
+        }
+    }
+
+    pub fn to_world(&self, x: u32, y: u32) -> (f32, f32) {
+        (x as f32 * self.tile_width, y as f32 * self.tile_height)
+    }
+
+    /// The tile a world position is in. Positions left of or above the grid have no tile.
+    pub fn to_tile(&self, x: f32, y: f32) -> Option<(u32, u32)> {
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+        Some(((x / self.tile_width) as u32, (y / self.tile_height) as u32))
+    }
+
+    pub fn position_of(&self, tile: &PositionTiled) -> Position {
+        let (x, y) = self.to_world(tile.x, tile.y);
+        Position::on_level(x, y, &tile.z_level)
+    }
+
+    pub fn tile_of(&self, position: &Position) -> Option<PositionTiled> {
+        self.to_tile(position.x, position.y)
+            .map(|(x, y)| PositionTiled::on_level(x, y, &position.z_level))
+    }
+}
+
+/// Keeps one of an entity's positions derived from the other, see `GridSyncSystem`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum GridSync {
+    /// `Position` follows `PositionTiled`.
+    FromTiled,
+    /// `PositionTiled` follows `Position`.
+    FromContinuous
+}
+
+/// Moves an entity one tile at a time. Steps are queued as offsets to the next tile and taken
+/// in order; the entity's `PositionTiled` moves to the next tile as soon as a step starts,
+/// while its `Position` slides there at `speed` tiles per second. A `speed` of 0 or less
+/// takes one whole step each tick. Steps onto solid tiles are dropped, and `blocked` is set
+/// until the next step is taken.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GridMover {
+    pub speed: f32,
+    #[serde(skip)]
+    steps: VecDeque<(i32, i32)>,
+    // The tile being left, and how far along the step is from 0.0 to 1.0
+    #[serde(skip)]
+    moving: Option<((u32, u32), f32)>,
+    #[serde(skip)]
+    pub blocked: bool
+}
+
+impl GridMover {
+    pub fn new(speed: f32) -> Self {
+        GridMover {
+            speed,
+            steps: VecDeque::new(),
+            moving: None,
+            blocked: false
+        }
+    }
+
+    pub fn push_step(&mut self, dx: i32, dy: i32) {
+        self.steps.push_back((dx, dy));
+    }
+
+    /// Queues the steps along `waypoints`, such as those of a `Path`, starting at `from`.
+    pub fn follow(&mut self, from: (u32, u32), waypoints: &[(u32, u32)]) {
+        let mut previous = from;
+        for &tile in waypoints {
+            self.push_step(tile.0 as i32 - previous.0 as i32, tile.1 as i32 - previous.1 as i32);
+            previous = tile;
+        }
+    }
+
+    /// Drops every queued step. A step that's already started is finished.
+    pub fn clear(&mut self) {
+        self.steps.clear();
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.moving.is_some()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.steps.len()
+    }
+}
+
+crate::define_component!(GridSync);
+crate::define_component!(GridMover);
+
+// Whether a step from `from` by `step` can be taken. Diagonal steps can't cut the corner of
+// a solid tile, the same as paths found with `Movement::EightWay`.
+fn can_step(map: Option<&TileMap>, from: (u32, u32), step: (i32, i32)) -> Option<(u32, u32)> {
+    let (x, y) = (from.0 as i64 + step.0 as i64, from.1 as i64 + step.1 as i64);
+    if x < 0 || y < 0 {
+        return None;
+    }
+    let map = match map {
+        Some(map) if map.width() > 0 => map,
+        _ => return Some((x as u32, y as u32))
+    };
+    let solid = |x: i64, y: i64| map.is_solid(x as u32, y as u32);
+    if solid(x, y) || (step.0 != 0 && step.1 != 0 && (solid(x, from.1 as i64) || solid(from.0 as i64, y))) {
+        return None;
+    }
+    Some((x as u32, y as u32))
+}
+
+// Writes `position` only if it's different, since every write to a flagged storage is an
+// event for its readers
+fn update_position(positions: &mut WriteStorage<Position>, entity: Entity, position: Position) {
+    let changed = match positions.get(entity) {
+        Some(p) => p.x != position.x || p.y != position.y || p.z_level != position.z_level,
+        None => true
+    };
+    if changed && positions.insert(entity, position).is_err() {
+        // Only dead entities can't be given one, and they don't need it
+    }
+}
+
+// Takes the queued steps of every `GridMover` and slides its `Position` between tiles. Any
+// time left over when a step finishes goes towards the next one.
+pub struct GridMovementSystem;
+
+impl<'a> System<'a> for GridMovementSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, GridConfig>,
+    Read<'a, ServerTime>,
+    Option<Read<'a, TileMap>>,
+    WriteStorage<'a, GridMover>,
+    WriteStorage<'a, PositionTiled>,
+    WriteStorage<'a, Position>);
+
+    fn run(&mut self, (entities, grid, time, map, mut movers, mut tiles, mut positions): Self::SystemData) {
+        let map = map.as_ref().map(|map| &**map);
+        let mut new_positions = vec!();
+
+        for (entity, mover, tile) in (&entities, &mut movers, &mut tiles).join() {
+            let mut travel = if mover.speed > 0.0 { mover.speed * time.delta as f32 } else { 1.0 };
+            loop {
+                if mover.moving.is_none() {
+                    let step = match mover.steps.pop_front() {
+                        Some(step) => step,
+                        None => break
+                    };
+                    match can_step(map, (tile.x, tile.y), step) {
+                        Some((x, y)) => {
+                            mover.moving = Some(((tile.x, tile.y), 0.0));
+                            mover.blocked = false;
+                            tile.x = x;
+                            tile.y = y;
+                        },
+                        None => {
+                            mover.blocked = true;
+                            continue;
+                        }
+                    }
+                }
+                let (from, progress) = mover.moving.unwrap();
+                if travel <= 0.0 {
+                    break;
+                }
+                let progress = progress + travel;
+                if progress < 1.0 {
+                    mover.moving = Some((from, progress));
+                    travel = 0.0;
+                } else {
+                    mover.moving = None;
+                    travel = progress - 1.0;
+                }
+            }
+
+            let (x, y) = grid.to_world(tile.x, tile.y);
+            let (x, y) = match mover.moving {
+                Some((from, progress)) => {
+                    let (fx, fy) = grid.to_world(from.0, from.1);
+                    (fx + (x - fx) * progress, fy + (y - fy) * progress)
+                },
+                None => (x, y)
+            };
+            new_positions.push((entity, Position::on_level(x, y, &tile.z_level)));
+        }
+
+        for (entity, position) in new_positions {
+            update_position(&mut positions, entity, position);
+        }
+    }
+}
+
+// Keeps the positions named by each entity's `GridSync` up to date, adding them if they're
+// missing. `Position`s of entities with a `GridMover` are left to `GridMovementSystem`.
+pub struct GridSyncSystem;
+
+impl<'a> System<'a> for GridSyncSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, GridConfig>,
+    ReadStorage<'a, GridSync>,
+    ReadStorage<'a, GridMover>,
+    WriteStorage<'a, PositionTiled>,
+    WriteStorage<'a, Position>);
+
+    fn run(&mut self, (entities, grid, syncs, movers, mut tiles, mut positions): Self::SystemData) {
+        let mut new_positions = vec!();
+        let mut new_tiles = vec!();
+
+        for (entity, sync, _) in (&entities, &syncs, !&movers).join() {
+            match *sync {
+                GridSync::FromTiled => if let Some(tile) = tiles.get(entity) {
+                    new_positions.push((entity, grid.position_of(tile)));
+                },
+                GridSync::FromContinuous => if let Some(tile) = positions.get(entity).and_then(|p| grid.tile_of(p)) {
+                    new_tiles.push((entity, tile));
+                }
+            }
+        }
+
+        for (entity, position) in new_positions {
+            update_position(&mut positions, entity, position);
+        }
+        for (entity, tile) in new_tiles {
+            let changed = match tiles.get(entity) {
+                Some(t) => t.x != tile.x || t.y != tile.y || t.z_level != tile.z_level,
+                None => true
+            };
+            if changed && tiles.insert(entity, tile).is_err() {
+                // Only dead entities can't be given one, and they don't need it
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::TileDef;
+    use std::collections::HashMap;
+
+    fn world(delta: f64) -> World {
+        let mut world = World::new();
+        world.register::<Position>();
+        world.register::<PositionTiled>();
+        world.register::<GridMover>();
+        world.register::<GridSync>();
+        world.add_resource(GridConfig::new(10.0, 10.0));
+        world.add_resource(ServerTime { delta, ..ServerTime::default() });
+        let mut map = TileMap::new(8, 8, 4);
+        map.define_tile(1, TileDef { sprite: 0, solid: true, cost: 1.0, properties: HashMap::new() });
+        map.add_layer("walls");
+        map.set_tile(0, 2, 1, 1);
+        world.add_resource(map);
+        world
+    }
+
+    fn mover(world: &mut World, speed: f32, steps: &[(i32, i32)]) -> Entity {
+        let mut mover = GridMover::new(speed);
+        for &(dx, dy) in steps {
+            mover.push_step(dx, dy);
+        }
+        world.create_entity().with(mover).with(PositionTiled::new(1, 1)).build()
+    }
+
+    fn at(world: &World, entity: Entity) -> ((u32, u32), (f32, f32)) {
+        let (tiles, positions) = (world.read_storage::<PositionTiled>(), world.read_storage::<Position>());
+        let (tile, p) = (tiles.get(entity).unwrap(), positions.get(entity).unwrap());
+        ((tile.x, tile.y), (p.x, p.y))
+    }
+
+    #[test]
+    fn movers_slide_between_tiles() {
+        let mut world = world(0.25);
+        let entity = mover(&mut world, 2.0, &[(0, 1)]);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 2), (10.0, 15.0)));
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 2), (10.0, 20.0)));
+        assert!(!world.read_storage::<GridMover>().get(entity).unwrap().is_moving());
+    }
+
+    #[test]
+    fn leftover_travel_goes_to_the_next_step() {
+        let mut world = world(0.75);
+        let entity = mover(&mut world, 2.0, &[(0, 1), (0, 1)]);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 3), (10.0, 25.0)));
+    }
+
+    #[test]
+    fn steps_into_walls_are_dropped() {
+        let mut world = world(0.5);
+        let entity = mover(&mut world, 2.0, &[(1, 0), (0, 1)]);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 2), (10.0, 20.0)));
+        let movers = world.read_storage::<GridMover>();
+        assert!(!movers.get(entity).unwrap().blocked);
+        assert_eq!(movers.get(entity).unwrap().queued(), 0);
+    }
+
+    #[test]
+    fn blocked_is_set_until_the_next_step() {
+        let mut world = world(0.5);
+        let entity = mover(&mut world, 2.0, &[(1, 0)]);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 1), (10.0, 10.0)));
+        assert!(world.read_storage::<GridMover>().get(entity).unwrap().blocked);
+    }
+
+    #[test]
+    fn diagonals_cant_cut_corners() {
+        let mut world = world(0.5);
+        let cutting = mover(&mut world, 2.0, &[(1, 1)]);
+        let open = mover(&mut world, 2.0, &[(-1, 1)]);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, cutting), ((1, 1), (10.0, 10.0)));
+        assert_eq!(at(&world, open), ((0, 2), (0.0, 20.0)));
+    }
+
+    #[test]
+    fn speed_zero_takes_a_step_a_tick() {
+        let mut world = world(0.1);
+        let entity = mover(&mut world, 0.0, &[(0, 1), (0, 1), (0, 1)]);
+        GridMovementSystem.run_now(&world.res);
+        // The second step starts as soon as the first one is done
+        assert_eq!(at(&world, entity), ((1, 3), (10.0, 20.0)));
+        GridMovementSystem.run_now(&world.res);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(at(&world, entity), ((1, 4), (10.0, 40.0)));
+        assert!(!world.read_storage::<GridMover>().get(entity).unwrap().is_moving());
+    }
+
+    #[test]
+    fn still_entities_arent_written() {
+        let mut world = world(0.5);
+        let mut reader = world.write_storage::<Position>().register_reader();
+        let entity = mover(&mut world, 2.0, &[]);
+        let synced = world.create_entity().with(PositionTiled::new(3, 3)).with(GridSync::FromTiled).build();
+        GridMovementSystem.run_now(&world.res);
+        GridSyncSystem.run_now(&world.res);
+        assert_eq!(world.read_storage::<Position>().channel().read(&mut reader).count(), 2);
+        assert_eq!(at(&world, synced), ((3, 3), (30.0, 30.0)));
+
+        for _ in 0..3 {
+            GridMovementSystem.run_now(&world.res);
+            GridSyncSystem.run_now(&world.res);
+        }
+        assert_eq!(world.read_storage::<Position>().channel().read(&mut reader).count(), 0);
+
+        world.write_storage::<GridMover>().get_mut(entity).unwrap().push_step(-1, 0);
+        GridMovementSystem.run_now(&world.res);
+        assert_eq!(world.read_storage::<Position>().channel().read(&mut reader).count(), 1);
+    }
+}
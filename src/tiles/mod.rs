@@ -0,0 +1,387 @@
+use crate::components::{Camera, Position, PositionTiled, SpriteID};
+use crate::utils::*;
+use specs::prelude::*;
+use shrev::EventChannel;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+mod path;
+mod tiled;
+mod grid;
+
+pub use path::*;
+pub use tiled::{TiledMap, TiledLayer, TiledObject, TiledTileset, MapProperties, MapObject};
+pub use grid::{GridConfig, GridSync, GridMover, GridMovementSystem, GridSyncSystem};
+
+pub type TileID = u16;
+
+/// The tile every layer is filled with to begin with. It has no definition and is never sent.
+pub const EMPTY_TILE: TileID = 0;
+
+pub type ChunkCoord = (u32, u32);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TileDef {
+    pub sprite: SpriteID,
+    #[serde(default)]
+    pub solid: bool,
+    /// How expensive the tile is to walk onto when finding paths. Where layers overlap the
+    /// highest cost is used.
+    #[serde(default = "default_cost")]
+    pub cost: f32,
+    #[serde(default)]
+    pub properties: HashMap<String, Value>
+}
+
+fn default_cost() -> f32 {
+    1.0
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TileChange {
+    pub layer: u16,
+    pub x: u32,
+    pub y: u32,
+    pub tile: TileID
+}
+
+/// A whole chunk of one layer, sent the first time it comes near a client.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChunkView {
+    pub layer: u16,
+    /// In chunks, not tiles.
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+    /// Tiles row by row, `size` by `size`.
+    pub tiles: Vec<TileID>
+}
+
+impl ChunkView {
+    /// Whether the tile at `x`, `y` (in tiles) is in this chunk.
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x / self.size == self.x && y / self.size == self.y
+    }
+}
+
+struct TileLayer {
+    name: String,
+    // Chunks that are all empty are never created
+    chunks: HashMap<ChunkCoord, Vec<TileID>>
+}
+
+// A grid of tiles with any number of layers, drawn in the order they were added. Layers are
+// split into square chunks so clients can be sent only the part of the map near them.
+// Every edit is published on `changes`, so systems can react to tiles changing.
+pub struct TileMap {
+    width: u32,
+    height: u32,
+    chunk_size: u32,
+    defs: BTreeMap<TileID, TileDef>,
+    layers: Vec<TileLayer>,
+    changes: EventChannel<TileChange>
+}
+
+impl Default for TileMap {
+    fn default() -> Self {
+        TileMap::new(0, 0, 16)
+    }
+}
+
+impl TileMap {
+    /// Panics if `chunk_size` is 0.
+    pub fn new(width: u32, height: u32, chunk_size: u32) -> Self {
+        assert!(chunk_size > 0, "Tile map chunks must be at least one tile across");
+        TileMap {
+            width,
+            height,
+            chunk_size,
+            defs: BTreeMap::new(),
+            layers: vec!(),
+            changes: EventChannel::new()
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub fn in_bounds(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height
+    }
+
+    pub fn define_tile(&mut self, id: TileID, def: TileDef) {
+        self.defs.insert(id, def);
+    }
+
+    pub fn tile_def(&self, id: TileID) -> Option<&TileDef> {
+        self.defs.get(&id)
+    }
+
+    pub fn tile_defs(&self) -> &BTreeMap<TileID, TileDef> {
+        &self.defs
+    }
+
+    /// Adds a layer on top of the others and returns its index.
+    pub fn add_layer(&mut self, name: &str) -> usize {
+        self.layers.push(TileLayer {
+            name: name.to_string(),
+            chunks: HashMap::new()
+        });
+        self.layers.len() - 1
+    }
+
+    pub fn layer_count(&self) -> usize {
+        self.layers.len()
+    }
+
+    pub fn layer_index(&self, name: &str) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.name == name)
+    }
+
+    pub fn layer_name(&self, layer: usize) -> &str {
+        &self.layers[layer].name
+    }
+
+    pub fn chunk_of(&self, x: u32, y: u32) -> ChunkCoord {
+        (x / self.chunk_size, y / self.chunk_size)
+    }
+
+    /// The number of chunks across and down.
+    pub fn chunk_bounds(&self) -> (u32, u32) {
+        ((self.width + self.chunk_size - 1) / self.chunk_size, (self.height + self.chunk_size - 1) / self.chunk_size)
+    }
+
+    fn index_in_chunk(&self, x: u32, y: u32) -> usize {
+        ((y % self.chunk_size) * self.chunk_size + x % self.chunk_size) as usize
+    }
+
+    pub fn get_tile(&self, layer: usize, x: u32, y: u32) -> TileID {
+        let chunk = self.chunk_of(x, y);
+        match self.layers[layer].chunks.get(&chunk) {
+            Some(tiles) => tiles[self.index_in_chunk(x, y)],
+            None => EMPTY_TILE
+        }
+    }
+
+    /// Changes one tile. Tiles outside the map are ignored.
+    pub fn set_tile(&mut self, layer: usize, x: u32, y: u32, tile: TileID) {
+        if !self.in_bounds(x, y) {
+            return;
+        }
+        let chunk = self.chunk_of(x, y);
+        let index = self.index_in_chunk(x, y);
+        let area = (self.chunk_size * self.chunk_size) as usize;
+
+        let tiles = self.layers[layer].chunks.entry(chunk)
+            .or_insert_with(|| vec![EMPTY_TILE; area]);
+        if tiles[index] == tile {
+            return;
+        }
+        tiles[index] = tile;
+
+        self.changes.single_write(TileChange {
+            layer: layer as u16,
+            x,
+            y,
+            tile
+        });
+    }
+
+    pub fn fill_rect(&mut self, layer: usize, x: u32, y: u32, width: u32, height: u32, tile: TileID) {
+        for ty in y..y + height {
+            for tx in x..x + width {
+                self.set_tile(layer, tx, ty, tile);
+            }
+        }
+    }
+
+    /// Whether any layer has a solid tile at this position. Everything outside the map is solid.
+    pub fn is_solid(&self, x: u32, y: u32) -> bool {
+        if !self.in_bounds(x, y) {
+            return true;
+        }
+        (0..self.layers.len()).any(|layer| {
+            self.tile_def(self.get_tile(layer, x, y)).map(|def| def.solid).unwrap_or(false)
+        })
+    }
+
+    pub fn chunk_view(&self, layer: usize, chunk: ChunkCoord) -> Option<ChunkView> {
+        self.layers[layer].chunks.get(&chunk).map(|tiles| ChunkView {
+            layer: layer as u16,
+            x: chunk.0,
+            y: chunk.1,
+            size: self.chunk_size,
+            tiles: tiles.clone()
+        })
+    }
+
+    pub fn changes(&self) -> &EventChannel<TileChange> {
+        &self.changes
+    }
+
+    /// Registers a reader for `changes`. Readers only see changes made after they were created.
+    pub fn track_changes(&mut self) -> ReaderId<TileChange> {
+        self.changes.register_reader()
+    }
+}
+
+// Sends every client the chunks within `radius` chunks of their camera the first time they
+// come into range, then only the tiles that change in them. Cameras are found by the
+// `PositionTiled` of the entity with the connection's `Camera`, or by its `Position` moved by
+// the camera's offset and converted to tiles with `GridConfig`, the same point `ViewSystem`
+// culls around. Connections without a camera are sent the whole map. Only connections that
+// already have a view this tick are sent anything.
+pub struct TileSyncSystem {
+    radius: u32,
+    reader: Option<ReaderId<TileChange>>,
+    sent: HashMap<String, HashSet<ChunkCoord>>
+}
+
+impl TileSyncSystem {
+    pub fn new(radius: u32) -> Self {
+        TileSyncSystem {
+            radius,
+            reader: None,
+            sent: HashMap::new()
+        }
+    }
+
+    fn chunks_near(&self, map: &TileMap, focus: Option<ChunkCoord>) -> HashSet<ChunkCoord> {
+        let (across, down) = map.chunk_bounds();
+        let (min, max) = match focus {
+            Some((cx, cy)) => ((cx.saturating_sub(self.radius), cy.saturating_sub(self.radius)),
+                               ((cx + self.radius).min(across.saturating_sub(1)), (cy + self.radius).min(down.saturating_sub(1)))),
+            None => ((0, 0), (across.saturating_sub(1), down.saturating_sub(1)))
+        };
+        let mut chunks = HashSet::new();
+        if across == 0 || down == 0 {
+            return chunks;
+        }
+        for cx in min.0..=max.0 {
+            for cy in min.1..=max.1 {
+                chunks.insert((cx, cy));
+            }
+        }
+        chunks
+    }
+}
+
+impl<'a> System<'a> for TileSyncSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, TileMap>,
+    ReadConnections<'a>,
+    Read<'a, GridConfig>,
+    ReadStorage<'a, Camera>,
+    ReadStorage<'a, Position>,
+    ReadStorage<'a, PositionTiled>,
+    WriteViewMap<'a>);
+
+    fn run(&mut self, (entities, map, connections, grid, cameras, positions, tiled_positions, mut views): Self::SystemData) {
+        let changes: Vec<TileChange> = map.changes().read(self.reader.as_mut().unwrap()).cloned().collect();
+
+        // Keys that reconnect get the definitions and chunks again
+        self.sent.retain(|key, _| connections.connections.iter().any(|conn| conn.key == *key));
+
+        let focuses: HashMap<&String, ChunkCoord> = (&entities, &cameras).join()
+            .filter_map(|(entity, camera)| {
+                let tile = match (tiled_positions.get(entity), positions.get(entity)) {
+                    (Some(p), _) => (p.x, p.y),
+                    // Anything left of or above the map is nearest its first tiles
+                    (None, Some(p)) => grid.to_tile((p.x + camera.offset.0 as f32).max(0.0), (p.y + camera.offset.1 as f32).max(0.0))?,
+                    (None, None) => return None
+                };
+                Some((&camera.key, map.chunk_of(tile.0, tile.1)))
+            })
+            .collect();
+
+        for (key, view) in views.iter_mut() {
+            let near = self.chunks_near(&map, focuses.get(key).cloned());
+            let first_view = !self.sent.contains_key(key);
+            let sent = self.sent.entry(key.clone()).or_insert_with(HashSet::new);
+
+            if first_view {
+                view.tile_defs = Some(map.tile_defs().clone());
+            }
+            for chunk in near.difference(sent) {
+                for layer in 0..map.layer_count() {
+                    if let Some(chunk_view) = map.chunk_view(layer, *chunk) {
+                        view.chunks.push(chunk_view);
+                    }
+                }
+            }
+            for change in &changes {
+                let chunk = map.chunk_of(change.x, change.y);
+                if sent.contains(&chunk) && near.contains(&chunk) {
+                    view.tile_changes.push(change.clone());
+                }
+            }
+            // Chunks that left the range are sent again in full if they come back
+            *sent = near;
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        <Self::SystemData as SystemData>::setup(res);
+        self.reader = Some(res.fetch_mut::<TileMap>().track_changes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{ClientView, Connection, ConnectionCollection};
+
+    fn sync(world: &World, system: &mut TileSyncSystem) -> ClientView {
+        world.write_resource::<ViewMap>().insert("p1".to_string(), ClientView::new());
+        system.run_now(&world.res);
+        world.write_resource::<ViewMap>().remove("p1").unwrap()
+    }
+
+    #[test]
+    fn chunks_are_sent_once_then_only_changes() {
+        let mut world = World::new();
+        let mut connections = ConnectionCollection::new();
+        connections.push(Connection { key: "p1".to_string() });
+        world.add_resource(connections);
+        let mut map = TileMap::new(8, 8, 4);
+        map.define_tile(1, TileDef { sprite: 3, solid: true, cost: 1.0, properties: HashMap::new() });
+        let layer = map.add_layer("ground");
+        map.fill_rect(layer, 0, 0, 8, 8, 1);
+        world.add_resource(map);
+        let mut system = TileSyncSystem::new(1);
+        System::setup(&mut system, &mut world.res);
+
+        let view = sync(&world, &mut system);
+        assert_eq!(view.tile_defs.map(|defs| defs.len()), Some(1));
+        assert_eq!(view.chunks.len(), 4);
+        assert!(view.tile_changes.is_empty());
+
+        for _ in 0..3 {
+            let view = sync(&world, &mut system);
+            assert!(view.tile_defs.is_none());
+            assert!(view.chunks.is_empty());
+            assert!(view.tile_changes.is_empty());
+        }
+
+        world.write_resource::<TileMap>().set_tile(layer, 5, 6, EMPTY_TILE);
+        let view = sync(&world, &mut system);
+        assert!(view.tile_defs.is_none());
+        assert!(view.chunks.is_empty());
+        assert_eq!(view.tile_changes.iter().map(|c| (c.x, c.y, c.tile)).collect::<Vec<_>>(), vec!((5, 6, EMPTY_TILE)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_cant_be_empty() {
+        TileMap::new(8, 8, 0);
+    }
+}
@@ -0,0 +1,428 @@
+use super::{TileChange, TileMap};
+use crate::components::PositionTiled;
+use specs::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+pub type TileCoord = (u32, u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Movement {
+    FourWay,
+    /// Diagonal steps can't cut the corner of a blocked tile.
+    EightWay
+}
+
+/// Asks for a path from the entity's `PositionTiled` to `goal`. It's taken off the entity
+/// straight away, and a `Path` or `NoPath` is added on a later tick.
+#[derive(Clone, Debug, Deserialize)]
+pub struct PathRequest {
+    pub goal: TileCoord,
+    pub movement: Movement
+}
+
+/// The tiles to walk through to reach `goal`, not including the one the entity started on.
+/// If a tile on the path stops being walkable, the path is replaced by a new `PathRequest`.
+#[derive(Clone, Debug)]
+pub struct Path {
+    pub waypoints: Vec<TileCoord>,
+    pub goal: TileCoord,
+    pub movement: Movement
+}
+
+#[derive(Clone, Debug)]
+pub struct NoPath {
+    pub goal: TileCoord
+}
+
+crate::define_component!(PathRequest);
+crate::define_component!(Path);
+crate::define_component!(NoPath);
+
+// The cost of stepping onto every tile of a map, with blocked tiles costing infinity.
+#[derive(Clone, Debug)]
+pub struct PathGrid {
+    width: u32,
+    height: u32,
+    costs: Vec<f32>,
+    // Keeps the heuristic admissible when tiles are cheaper than 1.0
+    min_cost: f32
+}
+
+impl PathGrid {
+    pub fn from_map(map: &TileMap) -> Self {
+        let mut grid = PathGrid {
+            width: map.width(),
+            height: map.height(),
+            costs: vec![1.0; (map.width() * map.height()) as usize],
+            min_cost: 1.0
+        };
+        for y in 0..map.height() {
+            for x in 0..map.width() {
+                grid.update(map, x, y);
+            }
+        }
+        grid
+    }
+
+    pub fn update(&mut self, map: &TileMap, x: u32, y: u32) {
+        if !self.in_bounds(x, y) {
+            return;
+        }
+        let cost = if map.is_solid(x, y) {
+            ::std::f32::INFINITY
+        } else {
+            // Tiles no layer defines cost 1.0
+            (0..map.layer_count())
+                .filter_map(|layer| map.tile_def(map.get_tile(layer, x, y)))
+                .map(|def| def.cost.max(0.0))
+                .max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal))
+                .unwrap_or(1.0)
+        };
+        if cost < self.min_cost {
+            self.min_cost = cost;
+        }
+        self.costs[(y * self.width + x) as usize] = cost;
+    }
+
+    pub fn in_bounds(&self, x: u32, y: u32) -> bool {
+        x < self.width && y < self.height
+    }
+
+    pub fn cost(&self, x: u32, y: u32) -> f32 {
+        if self.in_bounds(x, y) {
+            self.costs[(y * self.width + x) as usize]
+        } else {
+            ::std::f32::INFINITY
+        }
+    }
+
+    pub fn is_walkable(&self, x: u32, y: u32) -> bool {
+        self.cost(x, y).is_finite()
+    }
+
+    fn neighbours(&self, (x, y): TileCoord, movement: Movement) -> Vec<(TileCoord, f32)> {
+        let mut found = vec!();
+        let (x, y) = (x as i64, y as i64);
+        let walkable = |nx: i64, ny: i64| nx >= 0 && ny >= 0 && self.is_walkable(nx as u32, ny as u32);
+
+        for &(dx, dy) in &[(1, 0), (-1, 0), (0, 1), (0, -1)] {
+            if walkable(x + dx, y + dy) {
+                found.push((((x + dx) as u32, (y + dy) as u32), self.cost((x + dx) as u32, (y + dy) as u32)));
+            }
+        }
+        if movement == Movement::EightWay {
+            for &(dx, dy) in &[(1, 1), (1, -1), (-1, 1), (-1, -1)] {
+                if walkable(x + dx, y + dy) && walkable(x + dx, y) && walkable(x, y + dy) {
+                    let cost = self.cost((x + dx) as u32, (y + dy) as u32) * ::std::f32::consts::SQRT_2;
+                    found.push((((x + dx) as u32, (y + dy) as u32), cost));
+                }
+            }
+        }
+        found
+    }
+
+    fn heuristic(&self, from: TileCoord, to: TileCoord, movement: Movement) -> f32 {
+        let dx = (from.0 as f32 - to.0 as f32).abs();
+        let dy = (from.1 as f32 - to.1 as f32).abs();
+        let distance = match movement {
+            Movement::FourWay => dx + dy,
+            Movement::EightWay => dx.max(dy) + (::std::f32::consts::SQRT_2 - 1.0) * dx.min(dy)
+        };
+        distance * self.min_cost
+    }
+}
+
+#[derive(PartialEq)]
+struct Open {
+    estimate: f32,
+    tile: TileCoord
+}
+
+impl Eq for Open {}
+
+impl Ord for Open {
+    // Reversed so the heap pops the lowest estimate first
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.partial_cmp(&self.estimate).unwrap_or(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Open {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Finds the cheapest path from `start` to `goal` with A*. The path doesn't include `start`.
+pub fn find_path(grid: &PathGrid, start: TileCoord, goal: TileCoord, movement: Movement) -> Option<Vec<TileCoord>> {
+    if !grid.is_walkable(goal.0, goal.1) {
+        return None;
+    }
+    if start == goal {
+        return Some(vec!());
+    }
+
+    let mut open = BinaryHeap::new();
+    let mut came_from: HashMap<TileCoord, TileCoord> = HashMap::new();
+    let mut costs: HashMap<TileCoord, f32> = HashMap::new();
+    costs.insert(start, 0.0);
+    open.push(Open { estimate: grid.heuristic(start, goal, movement), tile: start });
+
+    while let Some(Open { tile, .. }) = open.pop() {
+        if tile == goal {
+            let mut path = vec!(goal);
+            let mut current = goal;
+            while let Some(previous) = came_from.get(&current) {
+                if *previous == start {
+                    break;
+                }
+                path.push(*previous);
+                current = *previous;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        let cost = costs[&tile];
+        for (next, step) in grid.neighbours(tile, movement) {
+            let next_cost = cost + step;
+            if costs.get(&next).map(|c| next_cost < *c).unwrap_or(true) {
+                costs.insert(next, next_cost);
+                came_from.insert(next, tile);
+                open.push(Open { estimate: next_cost + grid.heuristic(next, goal, movement), tile: next });
+            }
+        }
+    }
+    None
+}
+
+struct PathResult {
+    entity: Entity,
+    job: u64,
+    request: PathRequest,
+    waypoints: Option<Vec<TileCoord>>
+}
+
+// Solves `PathRequest`s on the rayon pool against a snapshot of the tile map, and hands the
+// results out on the tick after they finish. The snapshot is patched as tiles change, and
+// paths that cross a tile that became blocked are requested again.
+pub struct PathfindingSystem {
+    reader: Option<ReaderId<TileChange>>,
+    grid: Option<Arc<PathGrid>>,
+    sender: Sender<PathResult>,
+    receiver: Receiver<PathResult>,
+    // The latest job for each entity, so results of replaced requests are thrown away
+    pending: HashMap<Entity, u64>,
+    next_job: u64
+}
+
+impl PathfindingSystem {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        PathfindingSystem {
+            reader: None,
+            grid: None,
+            sender,
+            receiver,
+            pending: HashMap::new(),
+            next_job: 0
+        }
+    }
+
+    fn walkable(&self, waypoints: &[TileCoord]) -> bool {
+        let grid = self.grid.as_ref().unwrap();
+        waypoints.iter().all(|&(x, y)| grid.is_walkable(x, y))
+    }
+}
+
+impl<'a> System<'a> for PathfindingSystem {
+    type SystemData = (Entities<'a>,
+    Read<'a, TileMap>,
+    ReadStorage<'a, PositionTiled>,
+    WriteStorage<'a, PathRequest>,
+    WriteStorage<'a, Path>,
+    WriteStorage<'a, NoPath>);
+
+    fn run(&mut self, (entities, map, positions, mut requests, mut paths, mut no_paths): Self::SystemData) {
+        // Bring the snapshot up to date
+        let changes: Vec<TileChange> = map.changes().read(self.reader.as_mut().unwrap()).cloned().collect();
+        if self.grid.is_none() {
+            self.grid = Some(Arc::new(PathGrid::from_map(&map)));
+        } else if changes.len() > 0 {
+            // Jobs still running keep the old snapshot
+            let grid = Arc::make_mut(self.grid.as_mut().unwrap());
+            for change in &changes {
+                grid.update(&map, change.x, change.y);
+            }
+        }
+
+        // Ask again for paths that are now blocked
+        if changes.len() > 0 {
+            let changed: HashSet<TileCoord> = changes.iter().map(|c| (c.x, c.y)).collect();
+            let blocked: Vec<(Entity, PathRequest)> = (&entities, &paths).join()
+                .filter(|(_, path)| path.waypoints.iter().any(|tile| changed.contains(tile)))
+                .filter(|(_, path)| !self.walkable(&path.waypoints))
+                .map(|(entity, path)| (entity, PathRequest { goal: path.goal, movement: path.movement }))
+                .collect();
+            for (entity, request) in blocked {
+                paths.remove(entity);
+                let _ = requests.insert(entity, request);
+            }
+        }
+
+        // Forget jobs of entities that were deleted before their paths were found
+        self.pending.retain(|entity, _| entities.is_alive(*entity));
+
+        // Hand out finished paths
+        let finished: Vec<PathResult> = self.receiver.try_iter().collect();
+        for result in finished {
+            if self.pending.get(&result.entity) != Some(&result.job) {
+                continue;
+            }
+            self.pending.remove(&result.entity);
+            match result.waypoints {
+                // The map may have changed while the path was being found
+                Some(ref waypoints) if !self.walkable(waypoints) => {
+                    let _ = requests.insert(result.entity, result.request);
+                },
+                Some(waypoints) => {
+                    no_paths.remove(result.entity);
+                    let _ = paths.insert(result.entity, Path {
+                        waypoints,
+                        goal: result.request.goal,
+                        movement: result.request.movement
+                    });
+                },
+                None => {
+                    paths.remove(result.entity);
+                    let _ = no_paths.insert(result.entity, NoPath { goal: result.request.goal });
+                }
+            }
+        }
+
+        // Start new requests
+        let new: Vec<(Entity, TileCoord, PathRequest)> = (&entities, &positions, &requests).join()
+            .map(|(entity, p, request)| (entity, (p.x, p.y), request.clone()))
+            .collect();
+        for (entity, start, request) in new {
+            requests.remove(entity);
+            let job = self.next_job;
+            self.next_job += 1;
+            self.pending.insert(entity, job);
+
+            let grid = self.grid.as_ref().unwrap().clone();
+            let sender = self.sender.clone();
+            rayon::spawn(move || {
+                let waypoints = find_path(&grid, start, request.goal, request.movement);
+                let _ = sender.send(PathResult { entity, job, request, waypoints });
+            });
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        <Self::SystemData as SystemData>::setup(res);
+        self.reader = Some(res.fetch_mut::<TileMap>().track_changes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tiles::TileDef;
+
+    const WALL: u16 = 1;
+    const ROAD: u16 = 2;
+    const MUD: u16 = 3;
+
+    fn map(width: u32, height: u32) -> TileMap {
+        let mut map = TileMap::new(width, height, 16);
+        map.add_layer("ground");
+        for &(id, solid, cost) in &[(WALL, true, 1.0), (ROAD, false, 0.5), (MUD, false, 5.0)] {
+            map.define_tile(id, TileDef {
+                sprite: 0,
+                solid,
+                cost,
+                properties: HashMap::new()
+            });
+        }
+        map
+    }
+
+    #[test]
+    fn blocked_goal_has_no_path() {
+        let mut map = map(3, 3);
+        map.set_tile(0, 2, 2, WALL);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(find_path(&grid, (0, 0), (2, 2), Movement::EightWay), None);
+    }
+
+    #[test]
+    fn walled_in_goal_has_no_path() {
+        let mut map = map(3, 3);
+        map.fill_rect(0, 1, 0, 1, 3, WALL);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(find_path(&grid, (0, 0), (2, 2), Movement::FourWay), None);
+    }
+
+    #[test]
+    fn path_to_start_is_empty() {
+        let grid = PathGrid::from_map(&map(3, 3));
+        assert_eq!(find_path(&grid, (1, 1), (1, 1), Movement::FourWay), Some(vec!()));
+    }
+
+    #[test]
+    fn path_leaves_out_start() {
+        let grid = PathGrid::from_map(&map(3, 1));
+        assert_eq!(find_path(&grid, (0, 0), (2, 0), Movement::FourWay), Some(vec!((1, 0), (2, 0))));
+    }
+
+    #[test]
+    fn diagonals_take_open_corners() {
+        let grid = PathGrid::from_map(&map(3, 3));
+        assert_eq!(find_path(&grid, (0, 0), (2, 2), Movement::EightWay), Some(vec!((1, 1), (2, 2))));
+    }
+
+    #[test]
+    fn diagonals_dont_cut_blocked_corners() {
+        let mut map = map(3, 3);
+        map.set_tile(0, 1, 0, WALL);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(find_path(&grid, (0, 0), (1, 1), Movement::EightWay), Some(vec!((0, 1), (1, 1))));
+    }
+
+    #[test]
+    fn cheap_tiles_cost_less_than_one() {
+        let mut map = map(2, 1);
+        map.set_tile(0, 1, 0, ROAD);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(grid.cost(1, 0), 0.5);
+        assert_eq!(grid.cost(0, 0), 1.0);
+        assert_eq!(grid.min_cost, 0.5);
+    }
+
+    #[test]
+    fn paths_go_around_expensive_tiles() {
+        // Mud across the middle row, with a road along the top
+        let mut map = map(5, 3);
+        map.fill_rect(0, 1, 1, 3, 1, MUD);
+        map.fill_rect(0, 0, 0, 5, 1, ROAD);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(
+            find_path(&grid, (0, 1), (4, 1), Movement::FourWay),
+            Some(vec!((0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (4, 1)))
+        );
+    }
+
+    #[test]
+    fn paths_cross_expensive_tiles_when_shorter() {
+        let mut map = map(3, 3);
+        map.set_tile(0, 1, 1, MUD);
+        map.fill_rect(0, 0, 0, 3, 1, WALL);
+        map.fill_rect(0, 0, 2, 3, 1, WALL);
+        let grid = PathGrid::from_map(&map);
+        assert_eq!(find_path(&grid, (0, 1), (2, 1), Movement::FourWay), Some(vec!((1, 1), (2, 1))));
+    }
+}
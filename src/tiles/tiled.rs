@@ -0,0 +1,873 @@
+use super::{TileDef, TileID, TileMap};
+use crate::assets::AssetRegistry;
+use crate::components::Position;
+use crate::core::{BlueprintRegistry, Prefab};
+use serde_json::{Map, Value};
+use specs::prelude::*;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Read;
+
+// The top bits of a gid say how the tile is flipped or rotated, which tile maps don't store
+const GID_MASK: u32 = 0x0fff_ffff;
+
+/// The custom properties of a Tiled map, available as a resource once its objects are spawned.
+#[derive(Clone, Debug, Default)]
+pub struct MapProperties {
+    pub properties: HashMap<String, Value>
+}
+
+impl MapProperties {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.properties.get(name)
+    }
+}
+
+/// Added to every entity spawned from a Tiled object. `properties` holds the custom
+/// properties that weren't used as component fields.
+#[derive(Clone, Debug)]
+pub struct MapObject {
+    pub id: u64,
+    pub name: String,
+    pub layer: String,
+    pub width: f32,
+    pub height: f32,
+    pub properties: HashMap<String, Value>
+}
+
+crate::define_component!(MapObject);
+
+#[derive(Clone, Debug)]
+pub struct TiledTileset {
+    pub first_gid: u32,
+    pub name: String,
+    /// The properties of the tiles that have any, by their id within the tileset.
+    pub tiles: HashMap<u32, HashMap<String, Value>>
+}
+
+#[derive(Clone, Debug)]
+pub struct TiledObject {
+    pub id: u64,
+    pub name: String,
+    /// The object's class, or its type in maps saved before Tiled 1.9. Names the blueprint
+    /// it's spawned from.
+    pub kind: String,
+    /// The tile drawn for the object, or 0 if it isn't a tile object.
+    pub gid: u32,
+    /// The top-left corner, in pixels. Tiled saves tile objects by their bottom-left corner,
+    /// so they're moved up by their height when they're read.
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub properties: HashMap<String, Value>
+}
+
+#[derive(Clone, Debug)]
+pub enum TiledLayer {
+    /// Gids row by row, `width` by `height`, where 0 is no tile.
+    Tiles { name: String, width: u32, height: u32, gids: Vec<u32> },
+    Objects { name: String, objects: Vec<TiledObject> }
+}
+
+// A map made in the Tiled editor, read from its JSON (.tmj/.json) or XML (.tmx) format.
+// Layers inside groups are flattened in drawing order. Layer data can be CSV, XML tiles or
+// Base64, uncompressed or compressed with zlib or gzip. Loading fails for infinite maps and
+// for zstd-compressed layers, and image layers are skipped.
+//
+// Tile layers become `TileMap` layers, with gids used as tile ids. Tile definitions come from
+// the tiles' custom properties: `solid` and `cost` fill in the fields of the same name, and
+// `sprite` names the tile's sprite in the asset manifest, which defaults to
+// "<tileset name>/<tile id>".
+//
+// Objects are spawned from the blueprint named by their class, at their position in the map.
+// Custom properties named "Component.field" override that field of the blueprint, and the
+// rest are kept in the entity's `MapObject`. Objects without a class only get a `Position`.
+#[derive(Clone, Debug)]
+pub struct TiledMap {
+    /// In tiles.
+    pub width: u32,
+    pub height: u32,
+    /// In pixels.
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub properties: HashMap<String, Value>,
+    pub tilesets: Vec<TiledTileset>,
+    pub layers: Vec<TiledLayer>
+}
+
+impl TiledMap {
+    /// Loads a map, as XML if the file ends in ".tmx" and as JSON otherwise. External
+    /// tilesets are loaded relative to the map.
+    pub fn load_file(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Tiled map {} could not be read: {}", path, e))?;
+        let dir = ::std::path::Path::new(path).parent();
+        if path.ends_with(".tmx") {
+            TiledMap::from_tmx(&text, dir)
+        } else {
+            TiledMap::from_json(&text, dir)
+        }
+    }
+
+    /// Reads a map saved as JSON. The map can't use external tilesets.
+    pub fn load_json_str(json: &str) -> Result<Self, String> {
+        TiledMap::from_json(json, None)
+    }
+
+    /// Reads a map saved as TMX. The map can't use external tilesets.
+    pub fn load_tmx_str(xml: &str) -> Result<Self, String> {
+        TiledMap::from_tmx(xml, None)
+    }
+
+    /// Builds a tile map with a layer for each tile layer, and a definition for each tile
+    /// that's placed or has properties. Without `assets` every tile's sprite is 0.
+    pub fn tile_map(&self, chunk_size: u32, assets: Option<&AssetRegistry>) -> Result<TileMap, String> {
+        if chunk_size == 0 {
+            return Err("Tile map chunks must be at least one tile across".to_string());
+        }
+        let mut map = TileMap::new(self.width, self.height, chunk_size);
+        let mut used = BTreeSet::new();
+
+        for layer in &self.layers {
+            if let TiledLayer::Tiles { ref name, width, ref gids, .. } = *layer {
+                let index = map.add_layer(name);
+                for (i, gid) in gids.iter().enumerate() {
+                    let gid = gid & GID_MASK;
+                    if gid == 0 {
+                        continue;
+                    }
+                    if gid > TileID::max_value() as u32 {
+                        return Err(format!("Tile layer {} uses gid {}, which is too large for a tile id", name, gid));
+                    }
+                    map.set_tile(index, i as u32 % width, i as u32 / width, gid as TileID);
+                    used.insert(gid);
+                }
+            }
+        }
+        for tileset in &self.tilesets {
+            used.extend(tileset.tiles.keys()
+                .filter_map(|id| tileset.first_gid.checked_add(*id))
+                .filter(|gid| *gid <= TileID::max_value() as u32));
+        }
+
+        for gid in used {
+            map.define_tile(gid as TileID, self.tile_def(gid, assets));
+        }
+        Ok(map)
+    }
+
+    /// Spawns every object, and adds the map's properties as a `MapProperties` resource.
+    /// Nothing is spawned if any object's blueprint can't be built.
+    pub fn spawn_objects(&self, world: &mut World) -> Result<Vec<Entity>, String> {
+        let mut prefabs: Vec<(Option<Prefab>, MapObject, Position)> = vec!();
+        {
+            let registry = world.read_resource::<BlueprintRegistry>();
+            for layer in &self.layers {
+                if let TiledLayer::Objects { ref name, ref objects } = *layer {
+                    for object in objects {
+                        let (overrides, properties) = object.overrides();
+                        let prefab = if object.kind.is_empty() {
+                            None
+                        } else {
+                            Some(registry.prefab_with(&object.kind, &overrides)
+                                .map_err(|e| format!("Object {} on layer {} could not be spawned: {}", object.id, name, e))?)
+                        };
+                        prefabs.push((prefab, MapObject {
+                            id: object.id,
+                            name: object.name.clone(),
+                            layer: name.clone(),
+                            width: object.width,
+                            height: object.height,
+                            properties
+                        }, Position::new(object.x, object.y)));
+                    }
+                }
+            }
+        }
+
+        let mut spawned = vec!();
+        {
+            let entities = world.entities();
+            let lazy = world.read_resource::<LazyUpdate>();
+            for (prefab, map_object, position) in prefabs {
+                let entity = match prefab {
+                    Some(prefab) => prefab.build(&entities, &lazy),
+                    None => {
+                        let entity = entities.create();
+                        lazy.insert(entity, position);
+                        entity
+                    }
+                };
+                lazy.insert(entity, map_object);
+                spawned.push(entity);
+            }
+        }
+        world.add_resource(MapProperties {
+            properties: self.properties.clone()
+        });
+        world.maintain();
+        Ok(spawned)
+    }
+
+    fn tile_def(&self, gid: u32, assets: Option<&AssetRegistry>) -> TileDef {
+        let tileset = self.tilesets.iter()
+            .filter(|tileset| tileset.first_gid <= gid)
+            .max_by_key(|tileset| tileset.first_gid);
+        let (tileset_name, id, mut properties) = match tileset {
+            Some(tileset) => {
+                let id = gid - tileset.first_gid;
+                (tileset.name.as_str(), id, tileset.tiles.get(&id).cloned().unwrap_or_default())
+            },
+            None => ("", gid, HashMap::new())
+        };
+
+        let sprite_name = match properties.remove("sprite") {
+            Some(Value::String(name)) => name,
+            _ => format!("{}/{}", tileset_name, id)
+        };
+        TileDef {
+            sprite: assets.and_then(|assets| assets.sprite_id(&sprite_name)).unwrap_or(0),
+            solid: properties.remove("solid").and_then(|v| v.as_bool()).unwrap_or(false),
+            cost: properties.remove("cost").and_then(|v| v.as_f64()).unwrap_or(1.0) as f32,
+            properties
+        }
+    }
+
+    fn from_json(json: &str, dir: Option<&::std::path::Path>) -> Result<Self, String> {
+        let root: Value = serde_json::from_str(json)
+            .map_err(|e| format!("Tiled map could not be parsed: {}", e))?;
+        if root.get("infinite").and_then(Value::as_bool).unwrap_or(false) {
+            return Err("Infinite Tiled maps aren't supported".to_string());
+        }
+
+        let mut tilesets = vec!();
+        for tileset in root.get("tilesets").and_then(Value::as_array).into_iter().flatten() {
+            let first_gid = json_u32(tileset, "firstgid")?;
+            tilesets.push(match tileset.get("source").and_then(Value::as_str) {
+                Some(source) => load_tileset(first_gid, source, dir)?,
+                None => json_tileset(first_gid, tileset)
+            });
+        }
+
+        let mut layers = vec!();
+        json_layers(root.get("layers").unwrap_or(&Value::Null), &mut layers)?;
+        let tile_height = json_u32(&root, "tileheight")?;
+        move_tile_objects(&mut layers, tile_height);
+
+        Ok(TiledMap {
+            width: json_u32(&root, "width")?,
+            height: json_u32(&root, "height")?,
+            tile_width: json_u32(&root, "tilewidth")?,
+            tile_height,
+            properties: json_properties(&root),
+            tilesets,
+            layers
+        })
+    }
+
+    fn from_tmx(xml: &str, dir: Option<&::std::path::Path>) -> Result<Self, String> {
+        let root = parse_xml(xml)?;
+        if root.name != "map" {
+            return Err(format!("Tiled map has a <{}> where <map> was expected", root.name));
+        }
+        if root.attr("infinite") == Some("1") {
+            return Err("Infinite Tiled maps aren't supported".to_string());
+        }
+
+        let mut tilesets = vec!();
+        for tileset in root.children("tileset") {
+            let first_gid = xml_u32(tileset, "firstgid")?;
+            tilesets.push(match tileset.attr("source") {
+                Some(source) => load_tileset(first_gid, source, dir)?,
+                None => xml_tileset(first_gid, tileset)
+            });
+        }
+
+        let mut layers = vec!();
+        xml_layers(&root, &mut layers)?;
+        let tile_height = xml_u32(&root, "tileheight")?;
+        move_tile_objects(&mut layers, tile_height);
+
+        Ok(TiledMap {
+            width: xml_u32(&root, "width")?,
+            height: xml_u32(&root, "height")?,
+            tile_width: xml_u32(&root, "tilewidth")?,
+            tile_height,
+            properties: xml_properties(&root),
+            tilesets,
+            layers
+        })
+    }
+}
+
+impl TiledObject {
+    // Splits the properties into blueprint overrides and the ones left for `MapObject`
+    fn overrides(&self) -> (Value, HashMap<String, Value>) {
+        let mut position = Map::new();
+        position.insert("x".to_string(), Value::from(self.x));
+        position.insert("y".to_string(), Value::from(self.y));
+        let mut overrides = Map::new();
+        overrides.insert("Position".to_string(), Value::Object(position));
+
+        let mut rest = HashMap::new();
+        for (name, value) in &self.properties {
+            match name.find('.') {
+                Some(dot) => {
+                    let component = overrides.entry(name[..dot].to_string())
+                        .or_insert_with(|| Value::Object(Map::new()));
+                    if let Value::Object(ref mut fields) = *component {
+                        fields.insert(name[dot + 1..].to_string(), value.clone());
+                    }
+                },
+                None => {
+                    rest.insert(name.clone(), value.clone());
+                }
+            }
+        }
+        (Value::Object(overrides), rest)
+    }
+}
+
+fn load_tileset(first_gid: u32, source: &str, dir: Option<&::std::path::Path>) -> Result<TiledTileset, String> {
+    let dir = dir.ok_or_else(|| format!("External tileset {} can only be used by a map loaded from a file", source))?;
+    let path = dir.join(source);
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Tileset {} could not be read: {}", path.display(), e))?;
+    if source.ends_with(".tsx") {
+        Ok(xml_tileset(first_gid, &parse_xml(&text)?))
+    } else {
+        let tileset: Value = serde_json::from_str(&text)
+            .map_err(|e| format!("Tileset {} could not be parsed: {}", path.display(), e))?;
+        Ok(json_tileset(first_gid, &tileset))
+    }
+}
+
+fn gids_from_base64(data: &str, compression: &str) -> Result<Vec<u32>, String> {
+    let bytes = decode_base64(data)?;
+    let bytes = match compression {
+        "" => bytes,
+        "zlib" => decompress(ZlibDecoder::new(&bytes[..]), compression)?,
+        "gzip" => decompress(GzDecoder::new(&bytes[..]), compression)?,
+        _ => return Err(format!("Layer data compressed with {} isn't supported, save the map with zlib, gzip or no compression", compression))
+    };
+    if bytes.len() % 4 != 0 {
+        return Err(format!("Layer data has {} bytes, which isn't a whole number of gids", bytes.len()));
+    }
+    Ok(bytes.chunks(4)
+        .map(|gid| u32::from_le_bytes([gid[0], gid[1], gid[2], gid[3]]))
+        .collect())
+}
+
+fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let data: String = data.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::decode(&data).map_err(|e| format!("Layer data isn't valid Base64: {}", e))
+}
+
+fn decompress<R: Read>(mut decoder: R, compression: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec!();
+    decoder.read_to_end(&mut bytes)
+        .map_err(|e| format!("Layer data couldn't be decompressed with {}: {}", compression, e))?;
+    Ok(bytes)
+}
+
+// Tile objects are placed by their bottom-left corner, and their height can be left out when
+// it's the tile's own
+fn move_tile_objects(layers: &mut Vec<TiledLayer>, tile_height: u32) {
+    for layer in layers {
+        if let TiledLayer::Objects { ref mut objects, .. } = *layer {
+            for object in objects.iter_mut().filter(|object| object.gid != 0) {
+                if object.height == 0.0 {
+                    object.height = tile_height as f32;
+                }
+                object.y -= object.height;
+            }
+        }
+    }
+}
+
+fn tile_layer(name: String, width: u32, height: u32, gids: Vec<u32>) -> Result<TiledLayer, String> {
+    if gids.len() as u64 != width as u64 * height as u64 {
+        return Err(format!("Tile layer {} has {} tiles, but is {} by {}", name, gids.len(), width, height));
+    }
+    Ok(TiledLayer::Tiles { name, width, height, gids })
+}
+
+fn json_u32(value: &Value, key: &str) -> Result<u32, String> {
+    value.get(key)
+        .and_then(Value::as_u64)
+        .map(|n| n as u32)
+        .ok_or_else(|| format!("Tiled map is missing {}", key))
+}
+
+fn json_f32(value: &Value, key: &str) -> f32 {
+    value.get(key).and_then(Value::as_f64).unwrap_or(0.0) as f32
+}
+
+fn json_string(value: &Value, key: &str) -> String {
+    value.get(key).and_then(Value::as_str).unwrap_or("").to_string()
+}
+
+fn json_properties(value: &Value) -> HashMap<String, Value> {
+    value.get("properties").and_then(Value::as_array).into_iter().flatten()
+        .filter_map(|property| {
+            let name = property.get("name").and_then(Value::as_str)?;
+            Some((name.to_string(), property.get("value").cloned().unwrap_or(Value::Null)))
+        })
+        .collect()
+}
+
+fn json_tileset(first_gid: u32, tileset: &Value) -> TiledTileset {
+    TiledTileset {
+        first_gid,
+        name: json_string(tileset, "name"),
+        tiles: tileset.get("tiles").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(|tile| Some((tile.get("id").and_then(Value::as_u64)? as u32, json_properties(tile))))
+            .filter(|(_, properties)| !properties.is_empty())
+            .collect()
+    }
+}
+
+fn json_layers(layers: &Value, out: &mut Vec<TiledLayer>) -> Result<(), String> {
+    for layer in layers.as_array().into_iter().flatten() {
+        let name = json_string(layer, "name");
+        match layer.get("type").and_then(Value::as_str) {
+            Some("tilelayer") => {
+                let gids = match layer.get("data") {
+                    Some(Value::Array(data)) => data.iter()
+                        .map(|gid| match gid.as_u64() {
+                            Some(gid) if gid <= u32::max_value() as u64 => Ok(gid as u32),
+                            _ => Err(format!("Tile layer {} has {}, which isn't a gid", name, gid))
+                        })
+                        .collect::<Result<_, _>>()?,
+                    Some(Value::String(data)) => {
+                        let compression = layer.get("compression").and_then(Value::as_str).unwrap_or("");
+                        gids_from_base64(data, compression)?
+                    },
+                    _ => return Err(format!("Tile layer {} has no data", name))
+                };
+                let (width, height) = (json_u32(layer, "width")?, json_u32(layer, "height")?);
+                out.push(tile_layer(name, width, height, gids)?);
+            },
+            Some("objectgroup") => {
+                let objects = layer.get("objects").and_then(Value::as_array).into_iter().flatten()
+                    .map(|object| TiledObject {
+                        id: object.get("id").and_then(Value::as_u64).unwrap_or(0),
+                        name: json_string(object, "name"),
+                        kind: match object.get("class").and_then(Value::as_str) {
+                            Some(class) => class.to_string(),
+                            None => json_string(object, "type")
+                        },
+                        gid: object.get("gid").and_then(Value::as_u64).unwrap_or(0) as u32,
+                        x: json_f32(object, "x"),
+                        y: json_f32(object, "y"),
+                        width: json_f32(object, "width"),
+                        height: json_f32(object, "height"),
+                        properties: json_properties(object)
+                    })
+                    .collect();
+                out.push(TiledLayer::Objects { name, objects });
+            },
+            Some("group") => json_layers(layer.get("layers").unwrap_or(&Value::Null), out)?,
+            // Image layers have nothing to import
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn xml_u32(element: &Element, key: &str) -> Result<u32, String> {
+    element.attr(key)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| format!("<{}> in Tiled map is missing {}", element.name, key))
+}
+
+fn xml_f32(element: &Element, key: &str) -> f32 {
+    element.attr(key).and_then(|value| value.parse().ok()).unwrap_or(0.0)
+}
+
+fn xml_properties(element: &Element) -> HashMap<String, Value> {
+    let mut properties = HashMap::new();
+    for property in element.children("properties").flat_map(|p| p.children("property")) {
+        let name = match property.attr("name") {
+            Some(name) => name.to_string(),
+            None => continue
+        };
+        // Multi-line strings are stored as text instead of in `value`
+        let text = property.attr("value").unwrap_or(&property.text);
+        let value = match property.attr("type").unwrap_or("string") {
+            "int" => text.parse::<i64>().map(Value::from).unwrap_or(Value::Null),
+            "float" => text.parse::<f64>().map(Value::from).unwrap_or(Value::Null),
+            "bool" => Value::Bool(text == "true"),
+            "class" => Value::Object(xml_properties(property).into_iter().collect()),
+            _ => Value::String(text.to_string())
+        };
+        properties.insert(name, value);
+    }
+    properties
+}
+
+fn xml_tileset(first_gid: u32, tileset: &Element) -> TiledTileset {
+    TiledTileset {
+        first_gid,
+        name: tileset.attr("name").unwrap_or("").to_string(),
+        tiles: tileset.children("tile")
+            .filter_map(|tile| Some((tile.attr("id")?.parse().ok()?, xml_properties(tile))))
+            .filter(|(_, properties)| !properties.is_empty())
+            .collect()
+    }
+}
+
+fn xml_layers(parent: &Element, out: &mut Vec<TiledLayer>) -> Result<(), String> {
+    for layer in &parent.children {
+        let name = layer.attr("name").unwrap_or("").to_string();
+        match layer.name.as_str() {
+            "layer" => {
+                let data = layer.children("data").next()
+                    .ok_or_else(|| format!("Tile layer {} has no data", name))?;
+                let gids = match data.attr("encoding") {
+                    Some("csv") if data.text.trim().is_empty() => vec!(),
+                    Some("csv") => data.text.split(',')
+                        .map(|gid| gid.trim().parse()
+                            .map_err(|_| format!("Tile layer {} has {:?}, which isn't a gid", name, gid.trim())))
+                        .collect::<Result<_, _>>()?,
+                    Some("base64") => gids_from_base64(&data.text, data.attr("compression").unwrap_or(""))?,
+                    Some(encoding) => return Err(format!("Tile layer {} is encoded as {}, which isn't supported", name, encoding)),
+                    // Empty tiles have no gid
+                    None => data.children("tile")
+                        .map(|tile| match tile.attr("gid") {
+                            Some(gid) => gid.parse().map_err(|_| format!("Tile layer {} has {:?}, which isn't a gid", name, gid)),
+                            None => Ok(0)
+                        })
+                        .collect::<Result<_, _>>()?
+                };
+                let (width, height) = (xml_u32(layer, "width")?, xml_u32(layer, "height")?);
+                out.push(tile_layer(name, width, height, gids)?);
+            },
+            "objectgroup" => {
+                let objects = layer.children("object")
+                    .map(|object| TiledObject {
+                        id: object.attr("id").and_then(|id| id.parse().ok()).unwrap_or(0),
+                        name: object.attr("name").unwrap_or("").to_string(),
+                        kind: object.attr("class").or_else(|| object.attr("type")).unwrap_or("").to_string(),
+                        gid: object.attr("gid").and_then(|gid| gid.parse().ok()).unwrap_or(0),
+                        x: xml_f32(object, "x"),
+                        y: xml_f32(object, "y"),
+                        width: xml_f32(object, "width"),
+                        height: xml_f32(object, "height"),
+                        properties: xml_properties(object)
+                    })
+                    .collect();
+                out.push(TiledLayer::Objects { name, objects });
+            },
+            "group" => xml_layers(layer, out)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// The parts of a TMX or TSX element that maps are read from. Text is joined across CDATA
+// sections, and namespaces are dropped from names.
+struct Element {
+    name: String,
+    attributes: HashMap<String, String>,
+    children: Vec<Element>,
+    text: String
+}
+
+impl Element {
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|value| value.as_str())
+    }
+
+    fn children<'e>(&'e self, name: &'e str) -> impl Iterator<Item=&'e Element> + 'e {
+        self.children.iter().filter(move |child| child.name == name)
+    }
+}
+
+fn parse_xml(xml: &str) -> Result<Element, String> {
+    let document = roxmltree::Document::parse(xml)
+        .map_err(|e| format!("Tiled map isn't valid XML: {}", e))?;
+    Ok(to_element(document.root_element()))
+}
+
+fn to_element(node: roxmltree::Node) -> Element {
+    let mut element = Element {
+        name: node.tag_name().name().to_string(),
+        attributes: node.attributes().iter()
+            .map(|attribute| (attribute.name().to_string(), attribute.value().to_string()))
+            .collect(),
+        children: vec!(),
+        text: String::new()
+    };
+    for child in node.children() {
+        if child.is_element() {
+            element.children.push(to_element(child));
+        } else if child.is_text() {
+            element.text.push_str(child.text().unwrap_or(""));
+        }
+    }
+    element
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TMX: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Saved by hand -->
+<map version="1.10" orientation="orthogonal" width="2" height="2" tilewidth="16" tileheight="16" infinite="0">
+ <properties>
+  <property name="title" value="Caves &amp; Crypts"/>
+  <property name="level" type="int" value="3"/>
+  <property name="notes">line one
+line two</property>
+ </properties>
+ <tileset firstgid="1" name="terrain">
+  <tile id="1">
+   <properties>
+    <property name="solid" type="bool" value="true"/>
+   </properties>
+  </tile>
+ </tileset>
+ <layer id="1" name="ground" width="2" height="2">
+  <data encoding="csv">
+1,2,
+0,3
+</data>
+ </layer>
+ <group name="details">
+  <layer id="2" name="decor" width="2" height="2">
+   <data encoding="base64">
+    AQAAAAIAAAAAAAAAAwAAgA==
+   </data>
+  </layer>
+  <layer id="3" name="old" width="2" height="2">
+   <data>
+    <tile gid="3"/><tile/><tile/><tile gid="1"/>
+   </data>
+  </layer>
+ </group>
+ <objectgroup id="4" name="spawns">
+  <object id="7" name="boss" type="Orc" x="24" y="40.5" width="16" height="16">
+   <properties>
+    <property name="Health.max" type="int" value="50"/>
+    <property name="taunt"><![CDATA[Come <closer>]]></property>
+   </properties>
+  </object>
+ </objectgroup>
+</map>"#;
+
+    const JSON: &str = r#"{
+        "width": 2, "height": 2, "tilewidth": 16, "tileheight": 16, "infinite": false,
+        "properties": [{ "name": "level", "type": "int", "value": 3 }],
+        "tilesets": [{
+            "firstgid": 1, "name": "terrain",
+            "tiles": [{ "id": 1, "properties": [{ "name": "cost", "type": "float", "value": 2.5 }] }]
+        }],
+        "layers": [
+            { "type": "tilelayer", "name": "ground", "width": 2, "height": 2, "data": [1, 2, 0, 3] },
+            { "type": "group", "name": "details", "layers": [
+                { "type": "tilelayer", "name": "decor", "width": 2, "height": 2,
+                  "encoding": "base64", "data": "AQAAAAIAAAAAAAAAAwAAAA==" }
+            ]},
+            { "type": "imagelayer", "name": "sky" },
+            { "type": "objectgroup", "name": "spawns", "objects": [
+                { "id": 7, "name": "boss", "class": "Orc", "x": 24, "y": 40.5, "width": 16, "height": 16,
+                  "properties": [{ "name": "Health.max", "type": "int", "value": 50 }] }
+            ]}
+        ]
+    }"#;
+
+    fn gids(layer: &TiledLayer) -> &[u32] {
+        match *layer {
+            TiledLayer::Tiles { ref gids, .. } => gids,
+            TiledLayer::Objects { .. } => panic!("expected a tile layer")
+        }
+    }
+
+    fn objects(layer: &TiledLayer) -> &[TiledObject] {
+        match *layer {
+            TiledLayer::Objects { ref objects, .. } => objects,
+            TiledLayer::Tiles { .. } => panic!("expected an object layer")
+        }
+    }
+
+    #[test]
+    fn reads_tmx() {
+        let map = TiledMap::load_tmx_str(TMX).unwrap();
+        assert_eq!((map.width, map.height, map.tile_width, map.tile_height), (2, 2, 16, 16));
+        assert_eq!(map.properties["title"], Value::from("Caves & Crypts"));
+        assert_eq!(map.properties["level"], Value::from(3));
+        assert_eq!(map.properties["notes"], Value::from("line one\nline two"));
+        assert_eq!(map.tilesets[0].name, "terrain");
+        assert_eq!(map.tilesets[0].tiles[&1]["solid"], Value::Bool(true));
+
+        assert_eq!(map.layers.len(), 4);
+        assert_eq!(gids(&map.layers[0]), &[1, 2, 0, 3]);
+        // Flip bits are kept until the tile map is built
+        assert_eq!(gids(&map.layers[1]), &[1, 2, 0, 0x8000_0003]);
+        assert_eq!(gids(&map.layers[2]), &[3, 0, 0, 1]);
+
+        let boss = &objects(&map.layers[3])[0];
+        assert_eq!((boss.id, boss.name.as_str(), boss.kind.as_str()), (7, "boss", "Orc"));
+        assert_eq!((boss.x, boss.y, boss.width, boss.height), (24.0, 40.5, 16.0, 16.0));
+        assert_eq!(boss.properties["taunt"], Value::from("Come <closer>"));
+    }
+
+    #[test]
+    fn reads_json() {
+        let map = TiledMap::load_json_str(JSON).unwrap();
+        assert_eq!((map.width, map.height, map.tile_width, map.tile_height), (2, 2, 16, 16));
+        assert_eq!(map.properties["level"], Value::from(3));
+        assert_eq!(map.layers.len(), 3);
+        assert_eq!(gids(&map.layers[0]), &[1, 2, 0, 3]);
+        assert_eq!(gids(&map.layers[1]), &[1, 2, 0, 3]);
+
+        let boss = &objects(&map.layers[2])[0];
+        assert_eq!((boss.id, boss.kind.as_str(), boss.y), (7, "Orc", 40.5));
+        let (overrides, rest) = boss.overrides();
+        assert_eq!(overrides["Health"]["max"], Value::from(50));
+        assert_eq!(overrides["Position"]["x"], Value::from(24.0));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn builds_tile_map() {
+        let map = TiledMap::load_tmx_str(TMX).unwrap().tile_map(16, None).unwrap();
+        assert_eq!(map.layer_count(), 3);
+        assert_eq!(map.layer_index("decor"), Some(1));
+        assert_eq!((map.get_tile(0, 0, 0), map.get_tile(0, 1, 0), map.get_tile(0, 0, 1)), (1, 2, 0));
+        assert_eq!(map.get_tile(1, 1, 1), 3);
+        assert!(map.tile_def(2).unwrap().solid);
+        assert!(map.is_solid(1, 0));
+        assert!(!map.is_solid(0, 0));
+
+        let map = TiledMap::load_json_str(JSON).unwrap().tile_map(16, None).unwrap();
+        assert_eq!(map.tile_def(2).unwrap().cost, 2.5);
+        assert_eq!(map.tile_def(1).unwrap().cost, 1.0);
+    }
+
+    #[test]
+    fn decodes_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello".to_vec());
+        assert_eq!(decode_base64("aGVs\n bG8").unwrap(), b"hello".to_vec());
+        assert_eq!(decode_base64("").unwrap(), Vec::<u8>::new());
+        assert!(decode_base64("aGV*bG8=").is_err());
+        assert!(decode_base64("aGVsb").is_err());
+        assert!(gids_from_base64("aGVsbG8=", "").is_err());
+        assert!(gids_from_base64("AQAAAA==", "zlib").is_err());
+        assert_eq!(gids_from_base64("AQAAAA==", "").unwrap(), vec!(1));
+    }
+
+    #[test]
+    fn decompresses_layers() {
+        assert_eq!(gids_from_base64("eJxjZGBgYGKAAGYgBgAARAAH", "zlib").unwrap(), vec!(1, 2, 0, 3));
+        assert_eq!(gids_from_base64("H4sIAAAAAAACA2NkYGBgYoAAZiAGALXrXbwQAAAA", "gzip").unwrap(), vec!(1, 2, 0, 3));
+        assert!(gids_from_base64("eJxjZGBgYGKAAGYgBgAARAAH", "zstd").is_err());
+        assert!(gids_from_base64("eJxjZGBgYGKAAGYgBgAARAAH", "gzip").is_err());
+    }
+
+    #[test]
+    fn tile_objects_are_moved_to_their_top_left() {
+        let map = TiledMap::load_json_str(r#"{ "width": 4, "height": 4, "tilewidth": 16, "tileheight": 16,
+            "layers": [{ "type": "objectgroup", "name": "props", "objects": [
+                { "id": 1, "gid": 2, "x": 16, "y": 48, "width": 32, "height": 32 },
+                { "id": 2, "x": 16, "y": 48, "width": 32, "height": 32 }
+            ]}] }"#).unwrap();
+        let props = objects(&map.layers[0]);
+        assert_eq!((props[0].gid, props[0].x, props[0].y), (2, 16.0, 16.0));
+        assert_eq!((props[1].gid, props[1].x, props[1].y), (0, 16.0, 48.0));
+
+        let map = TiledMap::load_tmx_str(r#"<map width="4" height="4" tilewidth="16" tileheight="16">
+            <objectgroup name="props"><object id="1" gid="2" x="16" y="48"/></objectgroup>
+        </map>"#).unwrap();
+        let prop = &objects(&map.layers[0])[0];
+        assert_eq!((prop.y, prop.height), (32.0, 16.0));
+    }
+
+    #[test]
+    fn reads_entities_and_cdata() {
+        let element = parse_xml("<a b=\"&lt;&#65;&#x42;&quot;\">x &amp; <![CDATA[<y>]]>&apos;</a>").unwrap();
+        assert_eq!(element.attr("b"), Some("<AB\""));
+        assert_eq!(element.text, "x & <y>'");
+        assert!(parse_xml("<a>fish & chips</a>").is_err());
+        assert!(parse_xml("<a>&bogus;</a>").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_tmx() {
+        let malformed = [
+            "",
+            "not xml",
+            "<map",
+            "<map width=\"2\">",
+            "<map width=2></map>",
+            "<map width=\"2></map>",
+            "<map><layer></map>",
+            "<map></mpa>",
+            "<map><![CDATA[never closed</map>",
+            "<tileset></tileset>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\" infinite=\"1\"></map>",
+            "<map height=\"1\" tilewidth=\"16\" tileheight=\"16\"></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"/></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data encoding=\"csv\">1,x</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data encoding=\"csv\">1,2</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"0\" height=\"1\"><data encoding=\"csv\">1</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data encoding=\"base64\">!!!!</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data encoding=\"base64\" compression=\"gzip\">AQAAAA==</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data encoding=\"hex\">01</data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><layer name=\"a\" width=\"1\" height=\"1\"><data><tile gid=\"-1\"/></data></layer></map>",
+            "<map width=\"1\" height=\"1\" tilewidth=\"16\" tileheight=\"16\"><tileset firstgid=\"1\" source=\"terrain.tsx\"/></map>"
+        ];
+        for xml in malformed.iter() {
+            assert!(TiledMap::load_tmx_str(xml).is_err(), "{:?} was accepted", xml);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        let malformed = [
+            "",
+            "{",
+            "[]",
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "infinite": true }"#,
+            r#"{ "height": 1, "tilewidth": 16, "tileheight": 16 }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "tilesets": [{ "name": "no gid" }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1 }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1, "data": ["1"] }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1, "data": [1, 2] }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1, "data": "AQAAAAI" }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1, "data": "AQAAAA==", "compression": "zstd" }] }"#,
+            r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16, "tilesets": [{ "firstgid": 1, "source": "terrain.tsj" }] }"#
+        ];
+        for json in malformed.iter() {
+            assert!(TiledMap::load_json_str(json).is_err(), "{:?} was accepted", json);
+        }
+    }
+
+    #[test]
+    fn truncated_tmx_never_panics() {
+        for end in 0..TMX.len() {
+            if let Ok(map) = TiledMap::load_tmx_str(&TMX[..end]) {
+                let _ = map.tile_map(16, None);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_gids_too_large_for_tile_ids() {
+        let json = r#"{ "width": 1, "height": 1, "tilewidth": 16, "tileheight": 16,
+            "layers": [{ "type": "tilelayer", "name": "a", "width": 1, "height": 1, "data": [70000] }] }"#;
+        let map = TiledMap::load_json_str(json).unwrap();
+        assert!(map.tile_map(16, None).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_chunks() {
+        let map = TiledMap::load_json_str(JSON).unwrap();
+        assert!(map.tile_map(0, None).is_err());
+    }
+}
@@ -1,5 +1,8 @@
 use super::*;
 use super::core::*;
+use super::spatial::SpatialIndex;
+use super::assets::AssetRegistry;
+use super::tiles::TileMap;
 use std::collections::{HashMap, VecDeque};
 
 use std::net::TcpStream;
@@ -44,12 +47,22 @@ pub type ReadMessages<'a, E> = Read<'a, Messages<E>>;
 
 pub type WriteMessages<'a, E> = Write<'a, Messages<E>>;
 
-pub type InputMap = HashMap<String, VecDeque<Input>>;
+pub type ReadScheduler<'a, E> = Read<'a, Scheduler<E>>;
+
+pub type WriteScheduler<'a, E> = Write<'a, Scheduler<E>>;
+
+pub type InputMap = HashMap<String, VecDeque<SequencedInput>>;
 
 pub type ReadInputMap<'a> = Read<'a, InputMap>;
 
 pub type WriteInputMap<'a> = Write<'a, InputMap>;
 
+pub type ReadInputAcks<'a> = Read<'a, InputAcks>;
+
+pub type ReadInputStates<'a> = Read<'a, InputStates>;
+
+pub type ReadActionStates<'a, A> = Read<'a, ActionStates<A>>;
+
 pub type ViewMap = HashMap<String, ClientView>;
 
 pub type ReadViewMap<'a> = Read<'a, ViewMap>;
@@ -58,4 +71,18 @@ pub type WriteViewMap<'a> = Write<'a, ViewMap>;
 
 pub type ReadConnections<'a> = Read<'a, ConnectionCollection>;
 
-pub type WriteConnections<'a> = Write<'a, ConnectionCollection>;
\ No newline at end of file
+pub type WriteConnections<'a> = Write<'a, ConnectionCollection>;
+
+pub type ReadBlueprints<'a> = Read<'a, BlueprintRegistry>;
+
+pub type WriteBlueprints<'a> = Write<'a, BlueprintRegistry>;
+
+pub type ReadSpatialIndex<'a> = Read<'a, SpatialIndex>;
+
+pub type WriteSpatialIndex<'a> = Write<'a, SpatialIndex>;
+
+pub type ReadAssets<'a> = Read<'a, AssetRegistry>;
+
+pub type ReadTileMap<'a> = Read<'a, TileMap>;
+
+pub type WriteTileMap<'a> = Write<'a, TileMap>;
@@ -1,12 +1,49 @@
 use std::net::TcpStream;
 use bytes::{BufMut, BytesMut};
 use std::io::{Read, ErrorKind, Write};
-use crate::core::ClientView;
+use crate::core::{ClientView, Input};
+use crate::assets::AssetManifest;
 
 #[derive(Deserialize)]
 pub struct InputMessage {
+    /// Increases with every message, so the server can tell the client which inputs it has applied.
+    #[serde(default)]
+    pub seq: u64,
+    #[serde(default)]
     pub keys: Vec<char>,
-    pub clicks: Vec<(u32, u32)>
+    #[serde(default)]
+    pub clicks: Vec<(u32, u32)>,
+    /// Everything else, in the order it happened, applied after `keys` and `clicks`.
+    #[serde(default)]
+    pub events: Vec<Input>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ClientMessage {
+    /// Sent with the client's own clock to sync with the server, see `ServerMessage::Pong`.
+    Ping { ping: f64 },
+    Input(InputMessage)
+}
+
+// Everything the server sends is tagged with a "type" field, so clients can tell messages apart.
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum ServerMessage {
+    View(ClientView),
+    /// The reply to a ping. Half the round trip plus `server_time` estimates the server clock,
+    /// and clients should render views `interpolation_delay` seconds behind it.
+    Pong {
+        client_time: f64,
+        server_time: f64,
+        interpolation_delay: f64
+    },
+    /// Sent right after connecting. `manifest` is left out if the client said it already has
+    /// the manifest with this hash.
+    Manifest {
+        hash: String,
+        manifest: Option<AssetManifest>
+    }
 }
 
 fn find_stream_end_chars(msg: String) -> usize {
@@ -79,15 +116,19 @@ pub fn read_from_message_from_stream_nonblocking(stream: &mut TcpStream, buffer:
 }
 
 pub fn send_view_to_stream(stream: &mut TcpStream, view: ClientView) -> StreamWriteResult {
-    // Serialize view
-    let ser_view = serde_json::to_string(&view);
-    if ser_view.is_err() {
+    send_message_to_stream(stream, &ServerMessage::View(view))
+}
+
+pub fn send_message_to_stream(stream: &mut TcpStream, message: &ServerMessage) -> StreamWriteResult {
+    // Serialize message
+    let ser_msg = serde_json::to_string(message);
+    if ser_msg.is_err() {
         return StreamWriteResult::OtherError(
-            format!("Serialization of view failed: {}", ser_view.unwrap_err().to_string()))
+            format!("Serialization of message failed: {}", ser_msg.unwrap_err().to_string()))
     }
-    let ser_view = ser_view.unwrap() + "\n";
+    let ser_msg = ser_msg.unwrap() + "\n";
     loop {
-        match stream.write(ser_view.as_bytes()) {
+        match stream.write(ser_msg.as_bytes()) {
             Ok(_) => break,
             Err(e) => match e.kind() {
                 ErrorKind::WouldBlock => continue,